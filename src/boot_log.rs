@@ -0,0 +1,123 @@
+//! Copy of the log kept at the top of DRAM, so U-Boot or Linux can show what
+//! the M0 printed long after its UART output is gone. Like ramoops, the area
+//! has to be kept out of the next stage's memory map.
+//!
+//! Layout, all words little endian:
+//!
+//! | Offset | Contents                                 |
+//! |--------|------------------------------------------|
+//! | 0x00   | magic, `M0LG` in ASCII                   |
+//! | 0x04   | size of the data area in bytes           |
+//! | 0x08   | offset the next byte goes to             |
+//! | 0x0c   | number of times the data area wrapped    |
+//! | 0x10   | data area, plain text with `\n` newlines |
+//!
+//! Until DRAM is up messages collect in a small buffer of our own, `attach`
+//! moves them over.
+
+use core::cell::UnsafeCell;
+
+use crate::drivers::dram::{BOOT_LOG_SIZE, DRAM_BASE, DramSize};
+use crate::log::Sink;
+
+const BOOT_LOG_MAGIC: u32 = u32::from_le_bytes(*b"M0LG");
+const DATA_SIZE: usize = BOOT_LOG_SIZE - size_of::<Header>();
+const EARLY_SIZE: usize = 1024;
+
+#[repr(C)]
+struct Header {
+    magic: u32,
+    size: u32,
+    head: u32,
+    wraps: u32,
+}
+
+struct Ring {
+    /// Null until DRAM is usable
+    header: *mut Header,
+    head: usize,
+    wraps: usize,
+}
+
+struct State {
+    ring: Ring,
+    early: [u8; EARLY_SIZE],
+    early_len: usize,
+}
+
+struct StateCell(UnsafeCell<State>);
+
+unsafe impl Sync for StateCell {}
+
+static STATE: StateCell = StateCell(UnsafeCell::new(State {
+    ring: Ring {
+        header: core::ptr::null_mut(),
+        head: 0,
+        wraps: 0,
+    },
+    early: [0; EARLY_SIZE],
+    early_len: 0,
+}));
+
+pub struct BootLog;
+
+impl BootLog {
+    pub const fn base(size: DramSize) -> usize {
+        DRAM_BASE + size.bytes() - BOOT_LOG_SIZE
+    }
+
+    /// Starts writing to DRAM, anything logged so far is carried over. Early
+    /// messages past the first `EARLY_SIZE` bytes are lost.
+    pub unsafe fn attach(size: DramSize) {
+        let header = Self::base(size) as *mut Header;
+
+        unsafe {
+            header.write_volatile(Header {
+                magic: BOOT_LOG_MAGIC,
+                size: DATA_SIZE as u32,
+                head: 0,
+                wraps: 0,
+            });
+
+            let state = &mut *STATE.0.get();
+            state.ring.header = header;
+            state.ring.append(&state.early[..state.early_len]);
+        }
+    }
+}
+
+impl Ring {
+    unsafe fn append(&mut self, bytes: &[u8]) {
+        unsafe {
+            let data = self.header.add(1) as *mut u8;
+
+            for b in bytes {
+                data.add(self.head).write_volatile(*b);
+
+                self.head += 1;
+                if self.head == DATA_SIZE {
+                    self.head = 0;
+                    self.wraps += 1;
+                }
+            }
+
+            (&raw mut (*self.header).head).write_volatile(self.head as u32);
+            (&raw mut (*self.header).wraps).write_volatile(self.wraps as u32);
+        }
+    }
+}
+
+impl Sink for BootLog {
+    fn write(&self, bytes: &[u8]) {
+        let state = unsafe { &mut *STATE.0.get() };
+
+        if !state.ring.header.is_null() {
+            unsafe { state.ring.append(bytes) };
+            return;
+        }
+
+        let len = bytes.len().min(EARLY_SIZE - state.early_len);
+        state.early[state.early_len..state.early_len + len].copy_from_slice(&bytes[..len]);
+        state.early_len += len;
+    }
+}
@@ -0,0 +1,155 @@
+//! Record of what brought the loader down, kept at the top of DRAM so it
+//! survives a reset. The next boot reports and clears it once DRAM is up
+//! again, and the AP can look at it too. Only written with the `crash-log`
+//! feature, and only once DRAM is usable.
+//!
+//! Layout, all words little endian:
+//!
+//! | Offset | Contents                                     |
+//! |--------|----------------------------------------------|
+//! | 0x00   | magic, `M0CR` in ASCII                       |
+//! | 0x04   | kind, 1 for a HardFault, 2 for a panic       |
+//! | 0x08   | HardFault: stacked r0-r3, r12, lr, pc, xPSR  |
+//! | 0x08   | panic: line, column                          |
+//! | 0x10   | panic: file and message, each NUL terminated |
+//!
+//! Panic text that doesn't fit is cut short.
+
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::drivers::dram::DramSize;
+use crate::drivers::vectors::ExceptionFrame;
+use crate::handoff::{HANDOFF_SIZE, Handoff};
+use crate::log::warn;
+
+const ENABLED: bool = cfg!(feature = "crash-log");
+pub const CRASH_SIZE: usize = 256;
+
+const CRASH_MAGIC: u32 = u32::from_le_bytes(*b"M0CR");
+const KIND_HARD_FAULT: u32 = 1;
+const KIND_PANIC: u32 = 2;
+const TEXT_OFFSET: usize = 0x10;
+const TEXT_SIZE: usize = CRASH_SIZE - TEXT_OFFSET;
+
+/// Zero until DRAM is usable
+static BASE: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Crash;
+
+impl Crash {
+    /// Sits right above the handoff record.
+    pub const fn base(size: DramSize) -> usize {
+        Handoff::base(size) + HANDOFF_SIZE
+    }
+
+    /// Reports a crash left by the previous boot, then starts recording.
+    pub unsafe fn attach(size: DramSize) {
+        if !ENABLED {
+            return;
+        }
+
+        let record = Self::base(size) as *mut u32;
+        unsafe {
+            if record.read_volatile() == CRASH_MAGIC {
+                Self::report(record);
+            }
+
+            record.write_volatile(0);
+        }
+
+        BASE.store(record as usize, Ordering::Relaxed);
+    }
+
+    pub unsafe fn record_fault(frame: &ExceptionFrame) {
+        let Some(record) = Self::record() else {
+            return;
+        };
+
+        let regs = [
+            frame.r0, frame.r1, frame.r2, frame.r3, frame.r12, frame.lr, frame.pc, frame.xpsr,
+        ];
+
+        unsafe {
+            for (i, reg) in regs.iter().enumerate() {
+                record.add(2 + i).write_volatile(*reg);
+            }
+
+            Self::seal(record, KIND_HARD_FAULT);
+        }
+    }
+
+    pub unsafe fn record_panic(file: &str, line: u32, column: u32, message: &str) {
+        let Some(record) = Self::record() else {
+            return;
+        };
+
+        unsafe {
+            record.add(2).write_volatile(line);
+            record.add(3).write_volatile(column);
+
+            let text = (record as *mut u8).add(TEXT_OFFSET);
+            let bytes = file.bytes().chain([0]).chain(message.bytes()).chain([0]);
+            for (i, b) in bytes.take(TEXT_SIZE).enumerate() {
+                text.add(i).write_volatile(b);
+            }
+            // Keep the last string terminated however long it was
+            text.add(TEXT_SIZE - 1).write_volatile(0);
+
+            Self::seal(record, KIND_PANIC);
+        }
+    }
+
+    /// The magic goes in last, so a crash while recording doesn't leave a
+    /// valid looking record behind.
+    unsafe fn seal(record: *mut u32, kind: u32) {
+        unsafe {
+            record.add(1).write_volatile(kind);
+            record.write_volatile(CRASH_MAGIC);
+        }
+    }
+
+    unsafe fn report(record: *const u32) {
+        unsafe {
+            match record.add(1).read_volatile() {
+                KIND_HARD_FAULT => warn!(
+                    "Previous boot hit a HardFault at pc {:#x}, lr {:#x}",
+                    record.add(8).read_volatile(),
+                    record.add(7).read_volatile()
+                ),
+                KIND_PANIC => {
+                    let mut text = [0; TEXT_SIZE];
+                    let src = (record as *const u8).add(TEXT_OFFSET);
+                    for (i, b) in text.iter_mut().enumerate() {
+                        *b = src.add(i).read_volatile();
+                    }
+
+                    let mut strings = text.split(|b| *b == 0).map(|s| {
+                        // Cutting the text short may have split a character
+                        str::from_utf8(s).unwrap_or_else(|e| {
+                            str::from_utf8(&s[..e.valid_up_to()]).unwrap_or_default()
+                        })
+                    });
+                    let file = strings.next().unwrap_or_default();
+                    let message = strings.next().unwrap_or_default();
+
+                    warn!(
+                        "Previous boot panicked at {}:{}:{}: {}",
+                        file,
+                        record.add(2).read_volatile(),
+                        record.add(3).read_volatile(),
+                        message
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn record() -> Option<*mut u32> {
+        match BASE.load(Ordering::Relaxed) {
+            0 => None,
+            base => Some(base as *mut u32),
+        }
+    }
+}
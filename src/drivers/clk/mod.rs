@@ -46,6 +46,11 @@ pub trait Mux {
             writel(Self::ADDR, value)
         }
     }
+
+    /// Index of the current parent, in the order of the parent enum.
+    unsafe fn parent_index() -> usize {
+        (unsafe { readl(Self::ADDR) } >> Self::SHIFT) & genmask(Self::WIDTH - 1, 0)
+    }
 }
 
 pub trait Parents {
@@ -100,3 +105,39 @@ macro_rules! mux {
 pub(super) use gate;
 pub(super) use mux;
 pub(super) use parents;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::mmio::sim;
+
+    const REG: usize = 0x1000;
+
+    parents!(TestParents: A, B, C, D);
+    mux!(TestMux, REG, 4, 2, TestParents);
+    gate!(TestGate, REG, 1);
+
+    #[test]
+    fn mux_only_touches_its_field() {
+        sim::reset();
+        sim::set(REG, 0xffff_ffff);
+
+        unsafe { TestMux::set_parent(TestParents::B) };
+        assert_eq!(sim::get(REG), 0xffff_ffdf);
+
+        unsafe { TestMux::set_parent(TestParents::A) };
+        assert_eq!(sim::get(REG), 0xffff_ffcf);
+    }
+
+    #[test]
+    fn gate_toggles_its_bit() {
+        sim::reset();
+        sim::set(REG, 0x30);
+
+        unsafe { TestGate::ungate() };
+        assert_eq!(sim::get(REG), 0x32);
+
+        unsafe { TestGate::gate() };
+        assert_eq!(sim::get(REG), 0x30);
+    }
+}
@@ -1,32 +1,85 @@
-use crate::drivers::{StatelessDriver, bit, readl, writel};
+use crate::drivers::timer::Deadline;
+use crate::drivers::{bit, readl, writel};
+use crate::err::Error;
 
-pub(super) const TOPCRM_BASE: usize = 0x13b000;
+pub(crate) const TOPCRM_BASE: usize = 0x13b000;
 const TOPCRM_MPLL_CFG0: usize = TOPCRM_BASE + 0x008;
 const TOPCRM_UPLL_CFG0: usize = TOPCRM_BASE + 0x010;
 const TOPCRM_GPLL_CFG0: usize = TOPCRM_BASE + 0x110;
 const FLAG_LOCKED: usize = bit(30);
+const LOCK_TIMEOUT_MS: u64 = 100;
 
 pub struct PLL;
 
-impl StatelessDriver for PLL {
-    unsafe fn init() -> Self {
+pub struct PllStatus {
+    pub name: &'static str,
+    pub cfg: usize,
+    pub locked: bool,
+}
+
+impl PLL {
+    pub unsafe fn init() -> Result<Self, Error> {
         unsafe {
             writel(TOPCRM_MPLL_CFG0, 0x8040c11);
             writel(TOPCRM_UPLL_CFG0, 0x8347811);
             writel(TOPCRM_GPLL_CFG0, 0x8347d29);
+        }
 
-            while Self::is_locked(TOPCRM_MPLL_CFG0) {}
-            while Self::is_locked(TOPCRM_UPLL_CFG0) {}
-            while Self::is_locked(TOPCRM_GPLL_CFG0) {}
-
-            Self
+        let deadline = Deadline::after_ms(LOCK_TIMEOUT_MS);
+        for reg in [TOPCRM_MPLL_CFG0, TOPCRM_UPLL_CFG0, TOPCRM_GPLL_CFG0] {
+            deadline
+                .wait(|| unsafe { Self::is_locked(reg) })
+                .map_err(|_| Error::PLL)?;
         }
+
+        Ok(Self)
+    }
+
+    pub unsafe fn status() -> [PllStatus; 3] {
+        [
+            ("MPLL", TOPCRM_MPLL_CFG0),
+            ("UPLL", TOPCRM_UPLL_CFG0),
+            ("GPLL", TOPCRM_GPLL_CFG0),
+        ]
+        .map(|(name, reg)| {
+            let cfg = unsafe { readl(reg) };
+            PllStatus {
+                name,
+                cfg,
+                locked: cfg & FLAG_LOCKED != 0,
+            }
+        })
     }
-}
 
-impl PLL {
     #[inline(always)]
     unsafe fn is_locked(reg: usize) -> bool {
-        (unsafe { readl(reg) } & FLAG_LOCKED) == 0
+        (unsafe { readl(reg) } & FLAG_LOCKED) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::mmio::sim;
+
+    #[test]
+    fn programs_and_waits_for_all_plls() {
+        sim::reset();
+        for reg in [TOPCRM_MPLL_CFG0, TOPCRM_UPLL_CFG0, TOPCRM_GPLL_CFG0] {
+            sim::on_write(reg, |v| v | FLAG_LOCKED);
+        }
+
+        assert!(unsafe { PLL::init() }.is_ok());
+
+        assert_eq!(sim::get(TOPCRM_MPLL_CFG0), 0x8040c11 | FLAG_LOCKED);
+        assert_eq!(sim::get(TOPCRM_UPLL_CFG0), 0x8347811 | FLAG_LOCKED);
+        assert_eq!(sim::get(TOPCRM_GPLL_CFG0), 0x8347d29 | FLAG_LOCKED);
+    }
+
+    #[test]
+    fn times_out_without_lock() {
+        sim::reset();
+
+        assert!(matches!(unsafe { PLL::init() }, Err(Error::PLL)));
     }
 }
@@ -1,6 +1,6 @@
 use crate::drivers::{
     StatelessDriver,
-    clk::{Gate, Mux, gate, mux, parents, pll::TOPCRM_BASE},
+    clk::{Gate, Mux, Parents, gate, mux, parents, pll::TOPCRM_BASE},
     writel,
 };
 
@@ -36,9 +36,25 @@ impl StatelessDriver for SoCClocks {
 }
 
 parents!(M0Parents: Clk104m, Clk26m, Clk78m, Clk32k);
+const M0_RATES: [usize; M0Parents::COUNT] = [104_000_000, 26_000_000, 78_000_000, 32_768];
+/// No mux is known for the UARTs' work clock, they run off the 26 MHz
+/// reference whatever the muxes here are set to.
+const UART_RATE: usize = 26_000_000;
 mux!(M0Mux, TOPCRM_M0_SEL, 0, 2, M0Parents);
 gate!(M0Gate, TOPCRM_M0_SEL, 2);
 
+impl SoCClocks {
+    /// Rate the M0, and with it SysTick, currently runs at.
+    pub unsafe fn m0_rate() -> usize {
+        M0_RATES[unsafe { M0Mux::parent_index() }]
+    }
+
+    /// Input clock of the UARTs, their baud rate divisors follow from it.
+    pub fn uart_rate() -> usize {
+        UART_RATE
+    }
+}
+
 parents!(AHBParents: Clk104m, Clk26m, Clk78m, Clk32k);
 gate!(AHBUnkGate, TOPCRM_HS_CLK, 0);
 gate!(AHBUnk2Gate, TOPCRM_HS_CLK, 1);
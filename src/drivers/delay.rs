@@ -1,6 +1,16 @@
-#[inline(always)]
-pub fn nsdelay(count: u32) {
-    for _ in 0..count {
-        unsafe { core::arch::asm!("nop") };
+use crate::drivers::timer::Deadline;
+use crate::drivers::uart::Serial;
+
+/// Waits at least `us` microseconds, `Timer` has to be initialized.
+pub fn udelay(us: usize) {
+    // One more for the part of the current microsecond already gone
+    let deadline = Deadline::after_us(us as u64 + 1);
+    while !deadline.expired() {
+        // Waiting anyway, keep the console going
+        Serial::poll();
     }
 }
+
+pub fn mdelay(ms: usize) {
+    udelay(ms * 1000);
+}
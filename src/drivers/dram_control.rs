@@ -1,6 +1,6 @@
 use derive_ctor::ctor;
 
-use crate::drivers::{Driver, bit, dram::DramSize, shift, writel};
+use crate::drivers::{Driver, bit, dram::DramSize, regs::register, shift, writel};
 
 const DDR_CONTROL_BASE: usize = 0x150000;
 const DDR_CONTROL_MSTR: usize = DDR_CONTROL_BASE;
@@ -197,49 +197,32 @@ const DDR_CONTROL_PCCFG: usize = DDR_CONTROL_BASE + 0x400;
 
 pub const DDR_CONTROL_SWCTL: usize = DDR_CONTROL_BASE + 0x320;
 
-pub const DDR_CONTROL_PCTRL_0: usize = DDR_CONTROL_BASE + 0x490;
-pub const DDR_CONTROL_PCTRL_1: usize = DDR_CONTROL_BASE + 0x540;
-pub const DDR_CONTROL_PCTRL_2: usize = DDR_CONTROL_BASE + 0x5f0;
-pub const DDR_CONTROL_PCTRL_3: usize = DDR_CONTROL_BASE + 0x6a0;
+// Per AXI port registers, ports 0-3
+register!(pctrl[4], DDR_CONTROL_BASE + 0x490, stride: 0xb0, [
+    bit: PORT_EN, offset: 0;
+]);
 
-const DDR_CONTROL_PCFGR_0: usize = DDR_CONTROL_BASE + 0x404;
-shift!(SHIFT_RD_PORT_PRIO, 0);
-const FLAG_RD_PORT_AGING_ENABLE: usize = bit(12);
+register!(pcfgr[4], DDR_CONTROL_BASE + 0x404, stride: 0xb0, [
+    field: RD_PORT_PRIO, offset: 0, width: 10;
+    bit: RD_PORT_AGING_EN, offset: 12;
+]);
 
-const DDR_CONTROL_PCFGR_1: usize = DDR_CONTROL_BASE + 0x4b4;
-const DDR_CONTROL_PCFGR_2: usize = DDR_CONTROL_BASE + 0x564;
-const DDR_CONTROL_PCFGR_3: usize = DDR_CONTROL_BASE + 0x614;
+register!(pcfgw[4], DDR_CONTROL_BASE + 0x408, stride: 0xb0, [
+    field: WR_PORT_PRIO, offset: 0, width: 10;
+    bit: WR_PORT_AGING_EN, offset: 12;
+]);
 
-const DDR_CONTROL_PCFGW_0: usize = DDR_CONTROL_BASE + 0x408;
-shift!(SHIFT_WR_PORT_PRIO, 0);
-const FLAG_WR_PORT_AGING_ENABLE: usize = bit(12);
+register!(pcfgqos0[4], DDR_CONTROL_BASE + 0x494, stride: 0xb0, [
+    field: RQOS_MAP_LEVEL1, offset: 0, width: 4;
+    field: RQOS_MAP_REGION1, offset: 20, width: 2;
+]);
 
-const DDR_CONTROL_PCFGW_1: usize = DDR_CONTROL_BASE + 0x4b8;
-const DDR_CONTROL_PCFGW_2: usize = DDR_CONTROL_BASE + 0x568;
-const DDR_CONTROL_PCFGW_3: usize = DDR_CONTROL_BASE + 0x618;
+register!(pcfgqos1[4], DDR_CONTROL_BASE + 0x49c, stride: 0xb0);
+register!(pcfgwqos0[4], DDR_CONTROL_BASE + 0x498, stride: 0xb0);
+register!(pcfgwqos1[4], DDR_CONTROL_BASE + 0x4a0, stride: 0xb0);
 
-const DDR_CONTROL_PCFGQOS0_0: usize = DDR_CONTROL_BASE + 0x494;
-shift!(SHIFT_RQOS_MAP_LEVEL1, 0);
-shift!(SHIFT_RQOS_MAP_REGION1, 20);
-
-const DDR_CONTROL_PCFGQOS0_1: usize = DDR_CONTROL_BASE + 0x544;
-const DDR_CONTROL_PCFGQOS0_2: usize = DDR_CONTROL_BASE + 0x5f4;
-const DDR_CONTROL_PCFGQOS0_3: usize = DDR_CONTROL_BASE + 0x6a4;
-
-const DDR_CONTROL_PCFGQOS1_0: usize = DDR_CONTROL_BASE + 0x49c;
-const DDR_CONTROL_PCFGQOS1_1: usize = DDR_CONTROL_BASE + 0x54c;
-const DDR_CONTROL_PCFGQOS1_2: usize = DDR_CONTROL_BASE + 0x5fc;
-const DDR_CONTROL_PCFGQOS1_3: usize = DDR_CONTROL_BASE + 0x6ac;
-
-const DDR_CONTROL_PCFGWQOS0_0: usize = DDR_CONTROL_BASE + 0x498;
-const DDR_CONTROL_PCFGWQOS0_1: usize = DDR_CONTROL_BASE + 0x548;
-const DDR_CONTROL_PCFGWQOS0_2: usize = DDR_CONTROL_BASE + 0x5f8;
-const DDR_CONTROL_PCFGWQOS0_3: usize = DDR_CONTROL_BASE + 0x6a8;
-
-const DDR_CONTROL_PCFGWQOS1_0: usize = DDR_CONTROL_BASE + 0x4a0;
-const DDR_CONTROL_PCFGWQOS1_1: usize = DDR_CONTROL_BASE + 0x550;
-const DDR_CONTROL_PCFGWQOS1_2: usize = DDR_CONTROL_BASE + 0x600;
-const DDR_CONTROL_PCFGWQOS1_3: usize = DDR_CONTROL_BASE + 0x6b0;
+const PORT_RD_PRIO: [usize; pcfgr::COUNT] = [0x3ff, 0x20, 0, 4];
+const PORT_WR_PRIO: [usize; pcfgw::COUNT] = [0x3ff, 0xff, 0x3f, 0x5f];
 
 const DDR_CONTROL_PERFHPR1: usize = DDR_CONTROL_BASE + 0x25c;
 shift!(SHIFT_HPR_MAX_STARVE, 0);
@@ -491,66 +474,37 @@ impl DramControl {
 
     unsafe fn init_priority() {
         unsafe {
-            writel(
-                DDR_CONTROL_PCFGR_0,
-                SHIFT_RD_PORT_PRIO(0x3ff) | FLAG_RD_PORT_AGING_ENABLE,
-            );
-            writel(
-                DDR_CONTROL_PCFGR_1,
-                SHIFT_RD_PORT_PRIO(0x20) | FLAG_RD_PORT_AGING_ENABLE,
-            );
-            writel(
-                DDR_CONTROL_PCFGR_2,
-                SHIFT_RD_PORT_PRIO(0) | FLAG_RD_PORT_AGING_ENABLE,
-            );
-            writel(
-                DDR_CONTROL_PCFGR_3,
-                SHIFT_RD_PORT_PRIO(4) | FLAG_RD_PORT_AGING_ENABLE,
-            );
-            writel(
-                DDR_CONTROL_PCFGW_0,
-                SHIFT_WR_PORT_PRIO(0x3ff) | FLAG_WR_PORT_AGING_ENABLE,
-            );
-            writel(
-                DDR_CONTROL_PCFGW_1,
-                SHIFT_WR_PORT_PRIO(0xff) | FLAG_WR_PORT_AGING_ENABLE,
-            );
-            writel(
-                DDR_CONTROL_PCFGW_2,
-                SHIFT_WR_PORT_PRIO(0x3f) | FLAG_WR_PORT_AGING_ENABLE,
-            );
-            writel(
-                DDR_CONTROL_PCFGW_3,
-                SHIFT_WR_PORT_PRIO(0x5f) | FLAG_WR_PORT_AGING_ENABLE,
-            );
-            writel(
-                DDR_CONTROL_PCFGQOS0_0,
-                SHIFT_RQOS_MAP_LEVEL1(0xe) | SHIFT_RQOS_MAP_REGION1(2),
-            );
-            writel(
-                DDR_CONTROL_PCFGQOS0_1,
-                SHIFT_RQOS_MAP_LEVEL1(0xe) | SHIFT_RQOS_MAP_REGION1(2),
-            );
-            writel(
-                DDR_CONTROL_PCFGQOS0_2,
-                SHIFT_RQOS_MAP_LEVEL1(0xe) | SHIFT_RQOS_MAP_REGION1(2),
-            );
-            writel(
-                DDR_CONTROL_PCFGQOS0_3,
-                SHIFT_RQOS_MAP_LEVEL1(0xe) | SHIFT_RQOS_MAP_REGION1(2),
-            );
-            writel(DDR_CONTROL_PCFGQOS1_0, 0);
-            writel(DDR_CONTROL_PCFGQOS1_1, 0);
-            writel(DDR_CONTROL_PCFGQOS1_2, 0);
-            writel(DDR_CONTROL_PCFGQOS1_3, 0);
-            writel(DDR_CONTROL_PCFGWQOS0_0, 0);
-            writel(DDR_CONTROL_PCFGWQOS0_1, 0);
-            writel(DDR_CONTROL_PCFGWQOS0_2, 0);
-            writel(DDR_CONTROL_PCFGWQOS0_3, 0);
-            writel(DDR_CONTROL_PCFGWQOS1_0, 0);
-            writel(DDR_CONTROL_PCFGWQOS1_1, 0);
-            writel(DDR_CONTROL_PCFGWQOS1_2, 0);
-            writel(DDR_CONTROL_PCFGWQOS1_3, 0);
+            for (port, prio) in PORT_RD_PRIO.into_iter().enumerate() {
+                pcfgr(port).new_scope(|r| {
+                    r.set_field(pcfgr::RD_PORT_PRIO, prio)
+                        .set_bit(pcfgr::RD_PORT_AGING_EN);
+                });
+            }
+
+            for (port, prio) in PORT_WR_PRIO.into_iter().enumerate() {
+                pcfgw(port).new_scope(|r| {
+                    r.set_field(pcfgw::WR_PORT_PRIO, prio)
+                        .set_bit(pcfgw::WR_PORT_AGING_EN);
+                });
+            }
+
+            for port in 0..pcfgqos0::COUNT {
+                pcfgqos0(port).new_scope(|r| {
+                    r.set_field(pcfgqos0::RQOS_MAP_LEVEL1, 0xe)
+                        .set_field(pcfgqos0::RQOS_MAP_REGION1, 2);
+                });
+            }
+
+            for port in 0..pcfgqos1::COUNT {
+                pcfgqos1(port).write_raw(0);
+            }
+            for port in 0..pcfgwqos0::COUNT {
+                pcfgwqos0(port).write_raw(0);
+            }
+            for port in 0..pcfgwqos1::COUNT {
+                pcfgwqos1(port).write_raw(0);
+            }
+
             writel(
                 DDR_CONTROL_PERFHPR1,
                 SHIFT_HPR_MAX_STARVE(1) | SHIFT_HPR_XACT_RUN_LENGTH(0xf),
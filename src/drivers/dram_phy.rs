@@ -2,13 +2,12 @@ use derive_ctor::ctor;
 
 use crate::drivers::{
     Driver, bit,
-    delay::nsdelay,
-    dram::{DramSize, MATRIX_DDR_RESET},
-    dram_control::{
-        DDR_CONTROL_DFIMISC, DDR_CONTROL_PCTRL_0, DDR_CONTROL_PCTRL_1, DDR_CONTROL_PCTRL_2,
-        DDR_CONTROL_PCTRL_3, DDR_CONTROL_SWCTL,
-    },
-    readl, writel,
+    delay::udelay,
+    dram::{DramSize, MATRIX_DDR_RESET, SETTLE_US},
+    dram_control::{DDR_CONTROL_DFIMISC, DDR_CONTROL_SWCTL, pctrl},
+    readl,
+    watchdog::Watchdog,
+    writel,
 };
 
 const DDR_PHY_BASE: usize = 0x154000;
@@ -127,11 +126,12 @@ impl DramPhy {
     unsafe fn do_train(&self) {
         if self.size.is_dram_32_m() {
             for _ in 0..TRAINING_MAX_ATTEMPTS {
+                Watchdog::kick();
                 unsafe {
                     writel(DDR_PHY_TRAINING_CTRL, 0x01);
-                    nsdelay(200000);
+                    udelay(SETTLE_US);
                     writel(DDR_PHY_TRAINING_CTRL, 0x00);
-                    nsdelay(200000);
+                    udelay(SETTLE_US);
                 }
 
                 let fb = unsafe { readl(DDR_PHY_TRAINING_RESULT_0) };
@@ -143,11 +143,12 @@ impl DramPhy {
             }
         } else {
             for _ in 0..TRAINING_MAX_ATTEMPTS {
+                Watchdog::kick();
                 unsafe {
                     writel(DDR_PHY_TRAINING_CTRL, 0x01);
-                    nsdelay(200000);
+                    udelay(SETTLE_US);
                     writel(DDR_PHY_TRAINING_CTRL, 0x00);
-                    nsdelay(200000);
+                    udelay(SETTLE_US);
                 }
 
                 let fb = unsafe { readl(DDR_PHY_TRAINING_RESULT_0) };
@@ -172,30 +173,31 @@ impl DramPhy {
 
     pub unsafe fn train(&self) {
         unsafe {
-            nsdelay(200000);
+            udelay(SETTLE_US);
 
             writel(MATRIX_DDR_RESET, 0x0affffc0);
-            nsdelay(200000);
+            udelay(SETTLE_US);
 
             writel(DDR_CONTROL_SWCTL, 0x00);
-            nsdelay(200000);
+            udelay(SETTLE_US);
 
             writel(DDR_PHY_PLLOUT, 0x18);
-            nsdelay(200000);
+            udelay(SETTLE_US);
 
             writel(DDR_PHY_B_DQ8_15_PULL_UP_ODT, 0x04);
-            nsdelay(200000);
+            udelay(SETTLE_US);
 
             writel(DDR_CONTROL_DFIMISC, 0x01);
-            nsdelay(200000);
+            udelay(SETTLE_US);
             writel(DDR_CONTROL_DFIMISC, 0x00);
 
             self.do_train();
 
-            writel(DDR_CONTROL_PCTRL_0, 0x01);
-            writel(DDR_CONTROL_PCTRL_1, 0x01);
-            writel(DDR_CONTROL_PCTRL_2, 0x01);
-            writel(DDR_CONTROL_PCTRL_3, 0x01);
+            for port in 0..pctrl::COUNT {
+                pctrl(port).new_scope(|r| {
+                    r.set_bit(pctrl::PORT_EN);
+                });
+            }
         }
     }
 }
@@ -1,4 +1,5 @@
-use crate::drivers::{StatelessDriver, bit, dram::DramSize, readl, writel};
+use crate::drivers::{bit, dram::DramSize, readl, timer::Deadline, writel};
+use crate::err::Error;
 
 const EFUSE_BASE: usize = 0x121b000;
 const EFUSE_CONTROL: usize = EFUSE_BASE + 0x4;
@@ -6,22 +7,30 @@ const EFUSE_STATUS: usize = EFUSE_BASE + 0x14;
 
 const FLAG_BUSY: usize = bit(0);
 const FLAG_SETUP_NOT_DONE: usize = bit(1);
+const SETUP_TIMEOUT_MS: u64 = 100;
 
-const EFUSE_RAM_BASE: usize = EFUSE_BASE + 0x40;
+/// Shadow copy of the fuses, valid once `init` has run
+pub const EFUSE_RAM_BASE: usize = EFUSE_BASE + 0x40;
+pub const EFUSE_RAM_SIZE: usize = 0x40;
 const EFUSE_SECURE_FLAG: usize = EFUSE_RAM_BASE;
 
+#[derive(Default)]
 pub struct Efuse {
     pub secure: bool,
     pub dram_size: DramSize,
 }
 
-impl StatelessDriver for Efuse {
-    unsafe fn init() -> Self {
-        unsafe {
-            while readl(EFUSE_CONTROL) & FLAG_BUSY == 1 {}
-            writel(EFUSE_CONTROL, 1);
-            while readl(EFUSE_STATUS) & FLAG_SETUP_NOT_DONE == 0 {}
-        }
+impl Efuse {
+    pub unsafe fn init() -> Result<Self, Error> {
+        let deadline = Deadline::after_ms(SETUP_TIMEOUT_MS);
+
+        deadline
+            .wait(|| unsafe { readl(EFUSE_CONTROL) } & FLAG_BUSY == 0)
+            .map_err(|_| Error::Efuse)?;
+        unsafe { writel(EFUSE_CONTROL, 1) };
+        deadline
+            .wait(|| unsafe { readl(EFUSE_STATUS) } & FLAG_SETUP_NOT_DONE != 0)
+            .map_err(|_| Error::Efuse)?;
 
         let secure = (unsafe { readl(EFUSE_SECURE_FLAG) } & 0xff) != 0;
 
@@ -39,7 +48,7 @@ impl StatelessDriver for Efuse {
             _ => DramSize::Dram128M,
         };
 
-        Self { secure, dram_size }
+        Ok(Self { secure, dram_size })
     }
 }
 
@@ -65,3 +74,55 @@ const NYC_2G_4: usize = 0xF86317;
 
 const NYB_4G: usize = 0xF86313;
 const NYB_4G_2: usize = 0xF86315;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::mmio::sim;
+
+    unsafe fn read_efuse(word: usize) -> Efuse {
+        sim::reset();
+        sim::set(EFUSE_STATUS, FLAG_SETUP_NOT_DONE);
+        sim::set(EFUSE_SECURE_FLAG, word);
+
+        match unsafe { Efuse::init() } {
+            Ok(efuse) => efuse,
+            Err(_) => panic!("efuse setup timed out"),
+        }
+    }
+
+    #[test]
+    fn decodes_secure_flag() {
+        assert!(unsafe { read_efuse(0x01) }.secure);
+        assert!(!unsafe { read_efuse(0x00) }.secure);
+    }
+
+    #[test]
+    fn decodes_dram_size() {
+        let size = |chip: usize| unsafe { read_efuse(chip << 8) }.dram_size;
+
+        assert!(size(WINBOND_256M).is_dram_32_m());
+        assert!(size(ESMT_512M_2).is_dram_64_m());
+        assert!(size(NYC_2G_3).is_dram_256_m());
+        assert!(size(NYB_4G).is_dram_512_m());
+        assert!(size(0x123456).is_dram_128_m());
+    }
+
+    #[test]
+    fn times_out_on_busy_controller() {
+        sim::reset();
+        sim::set(EFUSE_CONTROL, FLAG_BUSY);
+
+        assert!(matches!(unsafe { Efuse::init() }, Err(Error::Efuse)));
+    }
+
+    #[test]
+    fn starts_efuse_read() {
+        sim::reset();
+        sim::set(EFUSE_STATUS, FLAG_SETUP_NOT_DONE);
+
+        assert!(unsafe { Efuse::init() }.is_ok());
+
+        assert_eq!(sim::get(EFUSE_CONTROL), 1);
+    }
+}
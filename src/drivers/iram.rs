@@ -2,8 +2,8 @@ use crate::drivers::{StatelessDriver, readl, writel};
 
 const IRAM1_BASE: usize = 0x102000;
 
-const IRAM2_BASE: usize = 0x82000400;
-const IRAM2_END: usize = 0x82003404;
+pub(super) const IRAM2_BASE: usize = 0x82000400;
+pub(super) const IRAM2_END: usize = 0x82003404;
 const IRAM2_SWITCH_ADDR: usize = 0x82002bc0;
 
 pub struct IRAM;
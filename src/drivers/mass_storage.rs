@@ -0,0 +1,416 @@
+use core::slice;
+
+use crate::{
+    drivers::{
+        dram::{DRAM_BASE, DramSize},
+        usb::{Identity, SetupPacket, Usb, UsbEvent},
+        watchdog::Watchdog,
+        zte_protocol::ZteProtocol,
+    },
+    err::{Error, USBError},
+    log::{info, warn},
+};
+
+const BLOCK_SIZE: usize = 512;
+
+const REQ_GET_MAX_LUN: u8 = 0xfe;
+const REQ_BOT_RESET: u8 = 0xff;
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CBW_LEN: usize = 31;
+const CBW_FLAG_IN: u8 = 0x80;
+
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CSW_PASSED: u8 = 0;
+const CSW_FAILED: u8 = 1;
+const CSW_PHASE_ERROR: u8 = 2;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_MODE_SENSE_6: u8 = 0x1a;
+const SCSI_START_STOP_UNIT: u8 = 0x1b;
+const SCSI_PREVENT_ALLOW_REMOVAL: u8 = 0x1e;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2a;
+const SCSI_VERIFY_10: u8 = 0x2f;
+
+const SENSE_ILLEGAL_REQUEST: u8 = 0x05;
+const ASC_INVALID_COMMAND: u8 = 0x20;
+const ASC_LBA_OUT_OF_RANGE: u8 = 0x21;
+
+const FLAG_START: u8 = 1 << 0;
+const FLAG_LOAD_EJECT: u8 = 1 << 1;
+
+const INQUIRY_DATA: [u8; 36] = *b"\x00\x80\x04\x02\x1f\x00\x00\x00openldr DRAM disk       0.1 ";
+const SENSE_LEN: usize = 18;
+const MODE_SENSE_LEN: usize = 4;
+const READ_CAPACITY_LEN: usize = 8;
+
+/// Which way data moves after a CBW and how much of it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DataPhase {
+    None,
+    In(usize),
+    Out(usize),
+}
+
+struct Cbw {
+    tag: u32,
+    length: u32,
+    flags: u8,
+    cb: [u8; 16],
+}
+
+impl Cbw {
+    /// `None` unless the CBW is both valid and meaningful to us: one LUN and
+    /// a command block length SCSI allows.
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() != CBW_LEN
+            || u32::from_le_bytes(data[0..4].try_into().ok()?) != CBW_SIGNATURE
+            || data[13] & 0x0f != 0
+            || !(1..=16).contains(&(data[14] & 0x1f))
+        {
+            return None;
+        }
+
+        Some(Self {
+            tag: u32::from_le_bytes(data[4..8].try_into().ok()?),
+            length: u32::from_le_bytes(data[8..12].try_into().ok()?),
+            flags: data[12],
+            cb: data[15..31].try_into().ok()?,
+        })
+    }
+
+    /// What the host expects.
+    fn data_phase(&self) -> DataPhase {
+        match (self.length as usize, self.flags & CBW_FLAG_IN != 0) {
+            (0, _) => DataPhase::None,
+            (len, true) => DataPhase::In(len),
+            (len, false) => DataPhase::Out(len),
+        }
+    }
+}
+
+enum Stage {
+    Command,
+    DataOut { addr: usize, remaining: usize },
+}
+
+/// Bulk-only mass storage gadget exposing DRAM as a single LUN. Ejecting the
+/// disk on the host boots the A53 from the start of DRAM.
+pub struct MassStorage {
+    usb: Usb,
+    size: DramSize,
+    stage: Stage,
+    tag: u32,
+    residue: u32,
+    sense: (u8, u8),
+}
+
+impl MassStorage {
+    /// pid.codes test VID/PID, the host binds by interface class anyway.
+    pub const IDENTITY: Identity = Identity {
+        vendor_id: 0x1209,
+        product_id: 0x0001,
+        // Mass storage, SCSI transparent, bulk-only
+        class: [0x08, 0x06, 0x50],
+        strings: ["openloader", "DRAM disk", "000000000001"],
+    };
+
+    pub fn new(usb: Usb, size: DramSize) -> Self {
+        Self {
+            usb,
+            size,
+            stage: Stage::Command,
+            tag: 0,
+            residue: 0,
+            sense: (0, 0),
+        }
+    }
+
+    pub unsafe fn dispatch(&mut self) -> Result<(), Error> {
+        unsafe {
+            self.usb.reconnect();
+
+            loop {
+                Watchdog::kick();
+
+                match self.step() {
+                    Ok(true) => break,
+                    Ok(false) => {}
+                    Err(e) if e.is_recoverable() => {
+                        warn!("Mass storage error: {}, resyncing", e);
+                        self.stage = Stage::Command;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            info!("Disk ejected, booting from {:#x}", DRAM_BASE);
+            ZteProtocol::boot_ap(DRAM_BASE);
+
+            Ok(())
+        }
+    }
+
+    /// Returns `true` once the host ejected the disk.
+    unsafe fn step(&mut self) -> Result<bool, Error> {
+        unsafe {
+            match self.usb.poll()? {
+                Some(UsbEvent::Reset | UsbEvent::Disconnect) => self.stage = Stage::Command,
+                Some(UsbEvent::Setup(setup)) => self.handle_setup(&setup)?,
+                Some(UsbEvent::BulkOut(len)) => return self.handle_bulk_out(len),
+                Some(UsbEvent::Suspend | UsbEvent::Resume) => {}
+                None => self.usb.wait(),
+            }
+        }
+
+        Ok(false)
+    }
+
+    unsafe fn handle_setup(&mut self, setup: &SetupPacket) -> Result<(), Error> {
+        unsafe {
+            match (setup[0], setup[1]) {
+                (0xa1, REQ_GET_MAX_LUN) => self.usb.ep0_write(&[0])?,
+                (0x21, REQ_BOT_RESET) => {
+                    self.stage = Stage::Command;
+                    self.usb.unwedge_bulk();
+                    self.usb.ep0_write(&[])?;
+                }
+                _ => self.usb.ep0_stall(),
+            }
+        }
+
+        Ok(())
+    }
+
+    unsafe fn handle_bulk_out(&mut self, len: usize) -> Result<bool, Error> {
+        match self.stage {
+            Stage::Command => {
+                let Some(cbw) = Cbw::parse(self.usb.rx_data()) else {
+                    // Nothing else goes through until the host does a reset
+                    // recovery
+                    warn!("Mass storage: invalid CBW, stalling");
+                    unsafe { self.usb.wedge_bulk() };
+                    return Ok(false);
+                };
+
+                unsafe { self.handle_command(&cbw) }
+            }
+            Stage::DataOut { addr, remaining } => {
+                let count = len.min(remaining);
+
+                // The host ended the data phase early
+                if count < remaining && len < self.usb.max_out_packet_size() {
+                    self.stage = Stage::Command;
+                    self.residue = self.residue.saturating_sub(count as u32);
+                    unsafe { self.send_status(CSW_FAILED)? };
+
+                    return Err(USBError::ShortPacket.into());
+                }
+
+                unsafe {
+                    slice::from_raw_parts_mut(addr as *mut u8, count)
+                        .copy_from_slice(&self.usb.rx_data()[..count]);
+                }
+                self.residue = self.residue.saturating_sub(count as u32);
+
+                if count == remaining {
+                    self.stage = Stage::Command;
+
+                    unsafe {
+                        // The host has more to send than we take, BOT case 11
+                        if self.residue > 0 {
+                            self.usb.halt_bulk_out();
+                        }
+                        self.send_status(CSW_PASSED)?;
+                    }
+                } else {
+                    self.stage = Stage::DataOut {
+                        addr: addr + count,
+                        remaining: remaining - count,
+                    };
+                }
+
+                Ok(false)
+            }
+        }
+    }
+
+    unsafe fn handle_command(&mut self, cbw: &Cbw) -> Result<bool, Error> {
+        let blocks = self.size.usable_bytes() / BLOCK_SIZE;
+
+        self.tag = cbw.tag;
+        self.residue = cbw.length;
+
+        let Some(intent) = Self::data_phase(&cbw.cb) else {
+            unsafe { self.fail(cbw, SENSE_ILLEGAL_REQUEST, ASC_INVALID_COMMAND)? };
+            return Ok(false);
+        };
+
+        // BOT cases 2, 3, 7, 8, 10 and 13: the host and us disagree on the
+        // direction, or it left too little room. Cases 4, 5, 9 and 11, where
+        // we move less than the host expects, are fine.
+        let agreed = match (cbw.data_phase(), intent) {
+            (_, DataPhase::None) => true,
+            (DataPhase::In(host), DataPhase::In(len)) => len <= host,
+            (DataPhase::Out(host), DataPhase::Out(len)) => len <= host,
+            _ => false,
+        };
+        if !agreed {
+            warn!("Mass storage: CBW disagrees on the data phase");
+            unsafe { self.finish(cbw, CSW_PHASE_ERROR)? };
+            return Ok(false);
+        }
+
+        // Never more than the command's allocation length
+        let reply = match intent {
+            DataPhase::In(len) => len,
+            _ => 0,
+        };
+
+        unsafe {
+            match cbw.cb[0] {
+                SCSI_TEST_UNIT_READY | SCSI_PREVENT_ALLOW_REMOVAL | SCSI_VERIFY_10 => {
+                    self.finish(cbw, CSW_PASSED)?
+                }
+                SCSI_INQUIRY => {
+                    self.send_data(&INQUIRY_DATA[..reply])?;
+                    self.send_status(CSW_PASSED)?;
+                }
+                SCSI_REQUEST_SENSE => {
+                    let (key, asc) = self.sense;
+                    let sense: [u8; SENSE_LEN] =
+                        [0x70, 0, key, 0, 0, 0, 0, 10, 0, 0, 0, 0, asc, 0, 0, 0, 0, 0];
+
+                    self.sense = (0, 0);
+                    self.send_data(&sense[..reply])?;
+                    self.send_status(CSW_PASSED)?;
+                }
+                SCSI_MODE_SENSE_6 => {
+                    let data: [u8; MODE_SENSE_LEN] = [3, 0, 0, 0];
+                    self.send_data(&data[..reply])?;
+                    self.send_status(CSW_PASSED)?;
+                }
+                SCSI_READ_CAPACITY_10 => {
+                    let mut data = [0u8; READ_CAPACITY_LEN];
+                    data[..4].copy_from_slice(&((blocks - 1) as u32).to_be_bytes());
+                    data[4..].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+
+                    self.send_data(&data[..reply])?;
+                    self.send_status(CSW_PASSED)?;
+                }
+                SCSI_READ_10 | SCSI_WRITE_10 => {
+                    let lba = u32::from_be_bytes([cbw.cb[2], cbw.cb[3], cbw.cb[4], cbw.cb[5]]);
+                    let count = u16::from_be_bytes([cbw.cb[7], cbw.cb[8]]) as usize;
+                    let addr = DRAM_BASE + lba as usize * BLOCK_SIZE;
+                    let len = count * BLOCK_SIZE;
+
+                    // usize is 32 bits here, so `lba + count` could wrap
+                    if lba as usize >= blocks || count > blocks - lba as usize {
+                        self.fail(cbw, SENSE_ILLEGAL_REQUEST, ASC_LBA_OUT_OF_RANGE)?;
+                    } else if len == 0 {
+                        self.finish(cbw, CSW_PASSED)?;
+                    } else if cbw.cb[0] == SCSI_READ_10 {
+                        self.send_data(slice::from_raw_parts(addr as *const u8, len))?;
+                        self.send_status(CSW_PASSED)?;
+                    } else {
+                        self.stage = Stage::DataOut {
+                            addr,
+                            remaining: len,
+                        };
+                    }
+                }
+                SCSI_START_STOP_UNIT => {
+                    self.finish(cbw, CSW_PASSED)?;
+
+                    if cbw.cb[4] & (FLAG_LOAD_EJECT | FLAG_START) == FLAG_LOAD_EJECT {
+                        return Ok(true);
+                    }
+                }
+                // Turned away above
+                _ => {}
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// What a command moves, `None` for commands we don't support.
+    fn data_phase(cb: &[u8; 16]) -> Option<DataPhase> {
+        let allocation = cb[4] as usize;
+        let blocks = u16::from_be_bytes([cb[7], cb[8]]) as usize * BLOCK_SIZE;
+
+        let phase = match cb[0] {
+            SCSI_TEST_UNIT_READY
+            | SCSI_PREVENT_ALLOW_REMOVAL
+            | SCSI_VERIFY_10
+            | SCSI_START_STOP_UNIT => DataPhase::None,
+            SCSI_INQUIRY => DataPhase::In(INQUIRY_DATA.len().min(allocation)),
+            SCSI_REQUEST_SENSE => DataPhase::In(SENSE_LEN.min(allocation)),
+            SCSI_MODE_SENSE_6 => DataPhase::In(MODE_SENSE_LEN.min(allocation)),
+            SCSI_READ_CAPACITY_10 => DataPhase::In(READ_CAPACITY_LEN),
+            SCSI_READ_10 => DataPhase::In(blocks),
+            SCSI_WRITE_10 => DataPhase::Out(blocks),
+            _ => return None,
+        };
+
+        Some(match phase {
+            DataPhase::In(0) | DataPhase::Out(0) => DataPhase::None,
+            phase => phase,
+        })
+    }
+
+    unsafe fn fail(&mut self, cbw: &Cbw, key: u8, asc: u8) -> Result<(), Error> {
+        self.sense = (key, asc);
+
+        unsafe { self.finish(cbw, CSW_FAILED) }
+    }
+
+    /// Ends a data phase the host expects but we have nothing for, then sends
+    /// the status.
+    unsafe fn finish(&mut self, cbw: &Cbw, status: u8) -> Result<(), Error> {
+        unsafe {
+            match cbw.data_phase() {
+                DataPhase::In(_) => self.send_data(&[])?,
+                DataPhase::Out(_) => self.usb.halt_bulk_out(),
+                DataPhase::None => {}
+            }
+            self.send_status(status)
+        }
+    }
+
+    /// Sends up to the host requested amount of data, terminating the data
+    /// phase with a short packet if we have less than that.
+    unsafe fn send_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        let len = data.len().min(self.residue as usize);
+        let mps = self.usb.max_packet_size();
+
+        unsafe {
+            for chunk in data[..len].chunks(mps) {
+                self.usb.bulk_write(chunk)?;
+            }
+
+            if len < self.residue as usize && len % mps == 0 {
+                self.usb.bulk_write(&[])?;
+            }
+        }
+        self.residue = self.residue.saturating_sub(len as u32);
+
+        Ok(())
+    }
+
+    unsafe fn send_status(&mut self, status: u8) -> Result<(), Error> {
+        let mut csw = [0u8; 13];
+        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        csw[8..12].copy_from_slice(&self.residue.to_le_bytes());
+        csw[12] = status;
+
+        unsafe { self.usb.bulk_write(&csw)? };
+
+        Ok(())
+    }
+}
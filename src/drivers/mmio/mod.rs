@@ -0,0 +1,63 @@
+//! Every device register access ends up here. On target these are plain
+//! volatile loads and stores, host tests get a simulated register file
+//! instead so drivers can run off-device.
+
+#[cfg(any(test, feature = "mmio-trace"))]
+use ufmt::{uDisplay, uwrite};
+
+#[cfg(test)]
+pub mod sim;
+#[cfg(feature = "mmio-trace")]
+pub mod trace;
+
+#[cfg(test)]
+pub(super) use sim::{read, write};
+
+/// One register access, printed as `R <addr> <value>` or `W <addr> <value>`.
+#[cfg(any(test, feature = "mmio-trace"))]
+#[derive(Clone, Copy)]
+pub struct Access {
+    pub write: bool,
+    pub addr: usize,
+    pub value: usize,
+}
+
+#[cfg(any(test, feature = "mmio-trace"))]
+impl uDisplay for Access {
+    fn fmt<W>(&self, f: &mut ufmt::Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: ufmt::uWrite + ?Sized,
+    {
+        let direction = if self.write { "W" } else { "R" };
+
+        uwrite!(f, "{} {:#x} {:#x}", direction, self.addr, self.value)
+    }
+}
+
+#[cfg(not(test))]
+#[inline(always)]
+pub(super) unsafe fn read(addr: usize) -> usize {
+    let value = unsafe { super::readl_raw(addr as *const usize) };
+
+    #[cfg(feature = "mmio-trace")]
+    trace::record(Access {
+        write: false,
+        addr,
+        value,
+    });
+
+    value
+}
+
+#[cfg(not(test))]
+#[inline(always)]
+pub(super) unsafe fn write(addr: usize, value: usize) {
+    #[cfg(feature = "mmio-trace")]
+    trace::record(Access {
+        write: true,
+        addr,
+        value,
+    });
+
+    unsafe { super::writel_raw(addr as *mut usize, value) };
+}
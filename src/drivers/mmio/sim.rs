@@ -0,0 +1,107 @@
+//! Simulated register file for host tests. Registers read back whatever was
+//! last written to them, or 0 if never touched. Hooks model the hardware side:
+//! a write hook decides what actually gets stored, a read hook what the CPU
+//! sees, e.g. to flag a PLL as locked or a FIFO as drained.
+//!
+//! SysTick's current value is the one register with a mind of its own, it
+//! counts down a tick on every read. Time passes that way for anything
+//! waiting on `Timer`, and a wait that never ends in a test times out.
+//!
+//! Every access is logged so tests can check the exact sequence a driver
+//! produced. State is per thread, which is also per test. Hooks must not call
+//! back into the simulator.
+
+use std::boxed::Box;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::vec::Vec;
+
+use crate::drivers::mmio::Access;
+use crate::drivers::timer::SYST_CVR;
+
+type Hook = Box<dyn FnMut(usize) -> usize>;
+
+#[derive(Default)]
+struct Sim {
+    regs: HashMap<usize, usize>,
+    read_hooks: HashMap<usize, Hook>,
+    write_hooks: HashMap<usize, Hook>,
+    log: Vec<Access>,
+    systick: usize,
+}
+
+thread_local! {
+    static SIM: RefCell<Sim> = RefCell::new(Sim::default());
+}
+
+/// Drops all register contents and hooks.
+pub fn reset() {
+    SIM.with_borrow_mut(|sim| *sim = Sim::default());
+}
+
+/// Presets a register without going through its hooks.
+pub fn set(addr: usize, value: usize) {
+    SIM.with_borrow_mut(|sim| sim.regs.insert(addr, value));
+}
+
+/// Current register contents, bypassing hooks.
+pub fn get(addr: usize) -> usize {
+    SIM.with_borrow(|sim| sim.regs.get(&addr).copied().unwrap_or(0))
+}
+
+/// Every access since the last `reset`, oldest first. Reads log the value
+/// the CPU saw, writes the value the CPU wrote.
+pub fn accesses() -> Vec<Access> {
+    SIM.with_borrow(|sim| sim.log.clone())
+}
+
+/// `hook` gets the stored value and returns what the read yields.
+pub fn on_read(addr: usize, hook: impl FnMut(usize) -> usize + 'static) {
+    SIM.with_borrow_mut(|sim| sim.read_hooks.insert(addr, Box::new(hook)));
+}
+
+/// `hook` gets the written value and returns what gets stored.
+pub fn on_write(addr: usize, hook: impl FnMut(usize) -> usize + 'static) {
+    SIM.with_borrow_mut(|sim| sim.write_hooks.insert(addr, Box::new(hook)));
+}
+
+pub(in crate::drivers) unsafe fn read(addr: usize) -> usize {
+    SIM.with_borrow_mut(|sim| {
+        let value = if addr == SYST_CVR {
+            sim.systick = sim.systick.wrapping_sub(1);
+            sim.systick
+        } else {
+            sim.regs.get(&addr).copied().unwrap_or(0)
+        };
+
+        let value = match sim.read_hooks.get_mut(&addr) {
+            Some(hook) => hook(value),
+            None => value,
+        };
+
+        sim.log.push(Access {
+            write: false,
+            addr,
+            value,
+        });
+
+        value
+    })
+}
+
+pub(in crate::drivers) unsafe fn write(addr: usize, value: usize) {
+    SIM.with_borrow_mut(|sim| {
+        sim.log.push(Access {
+            write: true,
+            addr,
+            value,
+        });
+
+        let value = match sim.write_hooks.get_mut(&addr) {
+            Some(hook) => hook(value),
+            None => value,
+        };
+
+        sim.regs.insert(addr, value);
+    });
+}
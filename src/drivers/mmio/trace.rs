@@ -0,0 +1,104 @@
+//! Records register accesses so a bring-up sequence can be compared against
+//! a trace of the vendor blob, see `tools/trace_diff.py`.
+//!
+//! The last `CAPACITY` accesses are kept in `MMIO_TRACE`, which sits in IRAM
+//! with the rest of our data and can be pulled out with a debugger. `dump`
+//! prints them over UART, with `mmio-trace-uart` every access is printed as
+//! it happens instead.
+//!
+//! Only meant for the single threaded bring-up, accesses made from interrupt
+//! handlers may garble an entry.
+
+use core::cell::UnsafeCell;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use ufmt::uwriteln;
+
+use crate::drivers::dram::{DRAM_BASE, DramSize};
+use crate::drivers::iram::{IRAM2_BASE, IRAM2_END};
+use crate::drivers::mmio::Access;
+use crate::drivers::timer::{SYST_BASE, SYST_END};
+use crate::drivers::uart::{self, Serial};
+use crate::drivers::watchdog::{WDT_BASE, WDT_END};
+
+const CAPACITY: usize = 1024;
+
+// Plain memory, console I/O, timer polling and watchdog kicks would drown
+// out the register accesses
+const UNTRACED: [Range<usize>; 4] = [
+    DRAM_BASE..DRAM_BASE + DramSize::Dram512M.bytes(),
+    IRAM2_BASE..IRAM2_END,
+    SYST_BASE..SYST_END,
+    WDT_BASE..WDT_END,
+];
+
+pub struct Trace {
+    entries: UnsafeCell<[Access; CAPACITY]>,
+    count: AtomicUsize,
+    paused: AtomicBool,
+}
+
+unsafe impl Sync for Trace {}
+
+#[unsafe(no_mangle)]
+pub static MMIO_TRACE: Trace = Trace {
+    entries: UnsafeCell::new(
+        [Access {
+            write: false,
+            addr: 0,
+            value: 0,
+        }; CAPACITY],
+    ),
+    count: AtomicUsize::new(0),
+    paused: AtomicBool::new(false),
+};
+
+fn is_traced(addr: usize) -> bool {
+    !UNTRACED.iter().any(|range| range.contains(&addr)) && !uart::console_regs().contains(&addr)
+}
+
+/// Runs `f` without recording the accesses it makes.
+fn paused<R>(f: impl FnOnce() -> R) -> R {
+    // No swap on thumbv6m, fine as long as nothing traces from interrupts
+    let was_paused = MMIO_TRACE.paused.load(Ordering::Relaxed);
+    MMIO_TRACE.paused.store(true, Ordering::Relaxed);
+    let r = f();
+    MMIO_TRACE.paused.store(was_paused, Ordering::Relaxed);
+
+    r
+}
+
+pub(super) fn record(access: Access) {
+    if MMIO_TRACE.paused.load(Ordering::Relaxed) || !is_traced(access.addr) {
+        return;
+    }
+
+    let seq = MMIO_TRACE.count.load(Ordering::Relaxed);
+    unsafe { (*MMIO_TRACE.entries.get())[seq % CAPACITY] = access };
+    MMIO_TRACE.count.store(seq + 1, Ordering::Relaxed);
+
+    #[cfg(feature = "mmio-trace-uart")]
+    paused(|| {
+        uwriteln!(&mut Serial, "MMIO {} {}", seq, access);
+    });
+}
+
+/// Prints the recorded accesses, oldest first.
+pub fn dump() {
+    paused(|| {
+        let count = MMIO_TRACE.count.load(Ordering::Relaxed);
+        let first = count.saturating_sub(CAPACITY);
+
+        uwriteln!(
+            &mut Serial,
+            "MMIO trace: {} accesses, dropped {}",
+            count,
+            first
+        );
+        for seq in first..count {
+            let access = unsafe { (*MMIO_TRACE.entries.get())[seq % CAPACITY] };
+            uwriteln!(&mut Serial, "MMIO {} {}", seq, access);
+        }
+    });
+}
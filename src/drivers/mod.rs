@@ -5,9 +5,20 @@ pub(super) mod dram_control;
 pub(super) mod dram_phy;
 pub mod efuse;
 pub mod iram;
+#[cfg(feature = "mass-storage")]
+pub mod mass_storage;
+pub mod mmio;
+#[cfg(feature = "interrupts")]
+pub mod nvic;
 pub(super) mod regs;
+pub mod reset;
+pub mod timer;
 pub mod uart;
 pub mod usb;
+pub mod vectors;
+pub mod watchdog;
+#[cfg(feature = "ymodem")]
+pub mod ymodem;
 pub mod zte_protocol;
 
 pub trait StatelessDriver {
@@ -31,11 +42,11 @@ pub(super) unsafe fn writel_raw<T>(reg: *mut T, value: T) {
 }
 
 pub(super) unsafe fn readl(reg: usize) -> usize {
-    unsafe { readl_raw(reg as *const usize) }
+    unsafe { mmio::read(reg) }
 }
 
 pub(super) unsafe fn writel(reg: usize, value: usize) {
-    unsafe { writel_raw(reg as *mut usize, value) };
+    unsafe { mmio::write(reg, value) };
 }
 
 pub(super) const fn bit(n: usize) -> usize {
@@ -43,7 +54,7 @@ pub(super) const fn bit(n: usize) -> usize {
 }
 
 pub(super) const fn genmask(h: usize, l: usize) -> usize {
-    (!0 << l) & (!0 >> (32 - 1 - h))
+    (!0 << l) & (!0 >> (usize::BITS as usize - 1 - h))
 }
 
 macro_rules! shift {
@@ -0,0 +1,132 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::drivers::vectors::{EXCEPTION_COUNT, IRQ_COUNT};
+use crate::drivers::{bit, readl, writel};
+
+const NVIC_ISER: usize = 0xe000e100;
+const NVIC_ICER: usize = 0xe000e180;
+const NVIC_ICPR: usize = 0xe000e280;
+const NVIC_IPR: usize = 0xe000e400;
+
+/// The M0 only implements the top two bits of each priority byte
+const PRIORITY_SHIFT: usize = 6;
+const PRIORITY_MASK: usize = 0x3;
+
+/// Entry points registered for each vector, zero where there is none
+static HANDLERS: [AtomicUsize; EXCEPTION_COUNT + IRQ_COUNT] =
+    [const { AtomicUsize::new(0) }; EXCEPTION_COUNT + IRQ_COUNT];
+
+/// Core exceptions drivers can hook, numbered as in the vector table.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum Exception {
+    SysTick = 15,
+}
+
+/// External interrupt lines as numbered on the M0's NVIC. The watchdog has
+/// none here, no interrupt of it is known so it can only reset.
+// No interrupt map for the M0 is known, these numbers are still to be
+// confirmed on hardware. A wrong one leaves the driver polling, or enables
+// some other peripheral's line that `dispatch` then masks again.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum Irq {
+    Uart1 = 2,
+    Usb = 12,
+}
+
+pub struct Nvic;
+
+impl Nvic {
+    /// Unmasks interrupts in the core, everything stays masked in the NVIC
+    /// until a driver asks for its line. Relies on `vectors::install`.
+    pub unsafe fn init() {
+        unsafe {
+            writel(NVIC_ICER, !0);
+            writel(NVIC_ICPR, !0);
+
+            asm!("cpsie i");
+        }
+    }
+
+    /// Has `handler` run for `irq` from now on, replacing any earlier one.
+    /// Takes effect once the line is enabled.
+    pub fn register(irq: Irq, handler: fn()) {
+        HANDLERS[EXCEPTION_COUNT + irq as usize].store(handler as usize, Ordering::Release);
+    }
+
+    /// Same for a core exception, which is always enabled. Without a handler
+    /// it is simply returned from.
+    pub fn register_exception(exception: Exception, handler: fn()) {
+        HANDLERS[exception as usize].store(handler as usize, Ordering::Release);
+    }
+
+    pub unsafe fn enable(irq: Irq) {
+        unsafe {
+            writel(NVIC_ICPR, bit(irq as usize));
+            writel(NVIC_ISER, bit(irq as usize));
+        }
+    }
+
+    pub unsafe fn disable(irq: Irq) {
+        unsafe { writel(NVIC_ICER, bit(irq as usize)) };
+    }
+
+    /// 0 is the most urgent and 3 the least, everything starts out at 0.
+    /// Only takes effect for an interrupt that isn't being handled.
+    pub unsafe fn set_priority(irq: Irq, priority: u8) {
+        let line = irq as usize;
+        // The priority registers only take word accesses on the M0
+        let reg = NVIC_IPR + line / 4 * 4;
+        let shift = line % 4 * 8 + PRIORITY_SHIFT;
+
+        // Four lines share the word, a handler changing another one's
+        // priority would otherwise get overwritten
+        critical_section(|| unsafe {
+            let value = readl(reg) & !(PRIORITY_MASK << shift);
+            writel(reg, value | ((priority as usize & PRIORITY_MASK) << shift));
+        });
+    }
+}
+
+/// Where every interrupt line's and hookable exception's vector points, hands
+/// over to the handler registered for it. A line without one is disabled, so
+/// it can't keep firing.
+pub(super) unsafe extern "C" fn dispatch() {
+    let ipsr: usize;
+    unsafe { asm!("mrs {}, ipsr", out(reg) ipsr) };
+
+    match HANDLERS[ipsr].load(Ordering::Acquire) {
+        0 if ipsr < EXCEPTION_COUNT => {}
+        0 => unsafe { writel(NVIC_ICER, bit(ipsr - EXCEPTION_COUNT)) },
+        handler => {
+            let handler: fn() = unsafe { core::mem::transmute(handler) };
+            handler();
+        }
+    }
+}
+
+/// Runs `f` with interrupts masked. A pending interrupt still wakes `wfi`
+/// inside, which is what makes check-then-sleep race free.
+#[inline(always)]
+pub fn critical_section<R>(f: impl FnOnce() -> R) -> R {
+    let primask: usize;
+    unsafe {
+        asm!("mrs {}, primask", out(reg) primask);
+        asm!("cpsid i");
+    }
+
+    let r = f();
+
+    if primask & 1 == 0 {
+        unsafe { asm!("cpsie i") };
+    }
+
+    r
+}
+
+#[inline(always)]
+pub fn wfi() {
+    unsafe { asm!("wfi") };
+}
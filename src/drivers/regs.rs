@@ -1,21 +1,142 @@
 use core::marker::PhantomData;
 
+use crate::drivers::{readl, writel};
+
+/// Access mode markers, a register or field only gets the accessors its mode
+/// allows so misuse fails to compile. The examples below are checked through
+/// `tools/regs_doctest.rs`.
+#[derive(Clone, Copy)]
+pub struct ReadWrite;
+/// Has no `write`:
+///
+/// ```compile_fail,E0425
+/// # mod drivers {
+/// #     pub unsafe fn readl(_: usize) -> usize { 0 }
+/// #     pub unsafe fn writel(_: usize, _: usize) {}
+/// #     pub mod regs { include!("regs.rs"); }
+/// #     use regs::register;
+/// register!(status, 0x1000, access: ro, [
+///     bit: READY, offset: 0;
+/// ]);
+///
+/// unsafe fn misuse() {
+///     unsafe { status::write_raw(0) };
+/// }
+/// # }
+/// # fn main() {}
+/// ```
+#[derive(Clone, Copy)]
+pub struct ReadOnly;
+/// Has no `read`:
+///
+/// ```compile_fail,E0425
+/// # mod drivers {
+/// #     pub unsafe fn readl(_: usize) -> usize { 0 }
+/// #     pub unsafe fn writel(_: usize, _: usize) {}
+/// #     pub mod regs { include!("regs.rs"); }
+/// #     use regs::register;
+/// register!(command, 0x1000, access: wo, [
+///     bit: START, offset: 0;
+/// ]);
+///
+/// unsafe fn misuse() -> usize {
+///     unsafe { command::read_raw() }
+/// }
+/// # }
+/// # fn main() {}
+/// ```
+#[derive(Clone, Copy)]
+pub struct WriteOnly;
+/// Reads return pending bits, writing a 1 clears them. Never read-modify-write
+/// these, that would acknowledge everything that happened to be set:
+///
+/// ```compile_fail,E0425
+/// # mod drivers {
+/// #     pub unsafe fn readl(_: usize) -> usize { 0 }
+/// #     pub unsafe fn writel(_: usize, _: usize) {}
+/// #     pub mod regs { include!("regs.rs"); }
+/// #     use regs::register;
+/// register!(pending, 0x1000, access: w1c, [
+///     bit: DONE, offset: 0;
+/// ]);
+///
+/// unsafe fn misuse() {
+///     unsafe {
+///         pending::read_modify_write(|r| {
+///             r.set_bit(pending::DONE);
+///         })
+///     };
+/// }
+/// # }
+/// # fn main() {}
+/// ```
+#[derive(Clone, Copy)]
+pub struct WriteOneToClear;
+
+pub trait Readable {}
+pub trait Writable {}
+pub trait Modifiable: Readable + Writable {}
+
+impl Readable for ReadWrite {}
+impl Writable for ReadWrite {}
+impl Modifiable for ReadWrite {}
+impl Readable for ReadOnly {}
+impl Writable for WriteOnly {}
+impl Readable for WriteOneToClear {}
+impl Writable for WriteOneToClear {}
+
+/// Legal values of an enumerated field, see `field_values!`.
+pub trait FieldValue: Copy {
+    fn from_bits(bits: usize) -> Option<Self>;
+    fn bits(self) -> usize;
+}
+
 #[derive(Clone, Copy)]
-pub struct Register<T>(usize, PhantomData<T>);
+pub struct Register<T, A = ReadWrite>(usize, PhantomData<(T, A)>);
 
-impl<T> Register<T> {
+impl<T, A> Register<T, A> {
     pub const fn new(addr: usize) -> Self {
         Self(addr, PhantomData)
     }
+}
 
+impl<T, A: Readable> Register<T, A> {
     #[inline(always)]
-    pub unsafe fn read(&self) -> usize {
-        unsafe { (self.0 as *const usize).read_volatile() }
+    pub unsafe fn read_raw(&self) -> usize {
+        unsafe { readl(self.0) }
     }
 
     #[inline(always)]
-    pub unsafe fn write(&self, val: usize) {
-        unsafe { (self.0 as *mut usize).write_volatile(val) };
+    pub unsafe fn read(&self) -> RegisterValue<T> {
+        RegisterValue::new(unsafe { self.read_raw() })
+    }
+}
+
+impl<T, A: Writable> Register<T, A> {
+    #[inline(always)]
+    pub unsafe fn write_raw(&self, val: usize) {
+        unsafe { writel(self.0, val) };
+    }
+
+    #[inline(always)]
+    pub unsafe fn write(&self, val: RegisterValue<T>) {
+        unsafe { self.write_raw(val.raw()) };
+    }
+
+    #[inline(always)]
+    pub unsafe fn new_scope<F: FnOnce(&mut RegisterValue<T>)>(&self, f: F) {
+        let mut v = RegisterValue::new(0);
+        f(&mut v);
+        unsafe { self.write(v) };
+    }
+}
+
+impl<T, A: Modifiable> Register<T, A> {
+    #[inline(always)]
+    pub unsafe fn read_modify_write<F: FnOnce(&mut RegisterValue<T>)>(&self, f: F) {
+        let mut v = unsafe { self.read() };
+        f(&mut v);
+        unsafe { self.write(v) };
     }
 }
 
@@ -33,33 +154,54 @@ impl<T> RegisterValue<T> {
     }
 
     #[inline(always)]
-    pub const fn set_field(&mut self, field: Field<T>, value: usize) -> &mut Self {
+    pub const fn set_field<A: Writable>(&mut self, field: Field<T, A>, value: usize) -> &mut Self {
         self.bits = field.set(self.bits, value);
         self
     }
 
     #[inline(always)]
-    pub const fn set_bit(&mut self, bit: Bit<T>) -> &mut Self {
+    pub fn set_enum<V: FieldValue, A: Writable>(
+        &mut self,
+        field: EnumField<T, V, A>,
+        value: V,
+    ) -> &mut Self {
+        self.bits = field.0.set(self.bits, value.bits());
+        self
+    }
+
+    #[inline(always)]
+    pub const fn set_bit<A: Writable>(&mut self, bit: Bit<T, A>) -> &mut Self {
         self.bits = bit.set(self.bits);
         self
     }
 
     #[inline(always)]
-    pub const fn clear_bit(&mut self, bit: Bit<T>) -> &mut Self {
+    pub const fn clear_bit<A: Writable>(&mut self, bit: Bit<T, A>) -> &mut Self {
         self.bits = bit.clear(self.bits);
         self
     }
 
     #[inline(always)]
-    pub const fn is_set_field(&self, field: Field<T>) -> bool {
+    pub const fn is_set_field<A: Readable>(&self, field: Field<T, A>) -> bool {
         field.is_set(self.bits)
     }
 
     #[inline(always)]
-    pub const fn is_set_bit(&self, bit: Bit<T>) -> bool {
+    pub const fn is_set_bit<A: Readable>(&self, bit: Bit<T, A>) -> bool {
         bit.is_set(self.bits)
     }
 
+    #[inline(always)]
+    pub const fn get_field<A: Readable>(&self, field: Field<T, A>) -> usize {
+        field.get(self.bits)
+    }
+
+    /// `None` if the hardware reports a value the enum doesn't know about.
+    #[inline(always)]
+    pub fn get_enum<V: FieldValue, A: Readable>(&self, field: EnumField<T, V, A>) -> Option<V> {
+        V::from_bits(field.0.get(self.bits))
+    }
+
     #[inline(always)]
     pub const fn raw(&self) -> usize {
         self.bits
@@ -67,13 +209,13 @@ impl<T> RegisterValue<T> {
 }
 
 #[derive(Clone, Copy)]
-pub struct Field<T> {
+pub struct Field<T, A = ReadWrite> {
     shift: usize,
     width: usize,
-    _marker: PhantomData<T>,
+    _marker: PhantomData<(T, A)>,
 }
 
-impl<T> Field<T> {
+impl<T, A> Field<T, A> {
     pub const fn new(shift: usize, width: usize) -> Self {
         Self {
             shift,
@@ -92,6 +234,11 @@ impl<T> Field<T> {
         reg & self.mask() != 0
     }
 
+    #[inline(always)]
+    pub const fn get(&self, reg: usize) -> usize {
+        (reg & self.mask()) >> self.shift
+    }
+
     #[inline(always)]
     pub const fn set(&self, reg: usize, val: usize) -> usize {
         (reg & !self.mask()) | ((val & ((1 << self.width) - 1)) << self.shift)
@@ -103,13 +250,23 @@ impl<T> Field<T> {
     }
 }
 
+/// A field that only takes the values of `V`.
 #[derive(Clone, Copy)]
-pub struct Bit<T> {
+pub struct EnumField<T, V, A = ReadWrite>(Field<T, A>, PhantomData<V>);
+
+impl<T, V, A> EnumField<T, V, A> {
+    pub const fn new(shift: usize, width: usize) -> Self {
+        Self(Field::new(shift, width), PhantomData)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Bit<T, A = ReadWrite> {
     shift: usize,
-    _marker: PhantomData<T>,
+    _marker: PhantomData<(T, A)>,
 }
 
-impl<T> Bit<T> {
+impl<T, A> Bit<T, A> {
     pub const fn new(shift: usize) -> Self {
         Self {
             shift,
@@ -139,68 +296,170 @@ impl<T> Bit<T> {
 }
 
 macro_rules! register {
+    // Registers are read/write unless marked `access: ro`, `wo` or `w1c`
     ($name:ident, $addr:expr) => {
+        register!($name, $addr, access: rw);
+    };
+
+    ($name:ident, $addr:expr, access: $access:ident) => {
         pub mod $name {
             use super::*;
             use crate::drivers::regs::Register;
 
             pub struct TypeLock;
-            pub const REG: Register<TypeLock> = Register::new($addr);
+            pub const REG: Register<TypeLock, register!(@access $access)> = Register::new($addr);
 
-            pub unsafe fn read() -> usize { unsafe { REG.read() } }
-            pub unsafe fn write(val: usize) { unsafe { REG.write(val) } }
+            register!(@raw_fns $access);
         }
     };
 
-    ($name:ident, $addr:expr, [ $( $kind:ident: $field:ident, offset: $shift:expr $(, width: $width:expr)? );* $(;)? ]) => {
+    ($name:ident, $addr:expr, [ $($fields:tt)* ]) => {
+        register!($name, $addr, access: rw, [ $($fields)* ]);
+    };
+
+    ($name:ident, $addr:expr, access: $access:ident, [ $( $kind:ident: $field:ident, offset: $shift:expr $(, width: $width:expr)? $(, values: $values:ty)? $(, access: $faccess:ident)? );* $(;)? ]) => {
         pub mod $name {
             use super::*;
             use crate::drivers::regs::{Register, RegisterValue};
 
             pub struct TypeLock;
-            pub const REG: Register<TypeLock> = Register::new($addr);
+            pub const REG: Register<TypeLock, register!(@access $access)> = Register::new($addr);
             type Value = RegisterValue<TypeLock>;
 
             $(
-                register!(@item $kind $field, $shift $(, $width)?);
+                register!(@item $kind $field, $shift, [$($width)?], [$($values)?], [$($faccess)?]);
             )*
 
-            #[inline(always)]
-            pub unsafe fn read() -> Value {
-                Value::new(unsafe { REG.read() })
-            }
+            register!(@fns $access);
+        }
+    };
 
-            #[inline(always)]
-            pub unsafe fn write(val: Value) {
-                unsafe { REG.write(val.raw()) }
-            }
+    // `count` instances spaced `stride` bytes apart, `name(n)` hands out the
+    // n-th one with the same API a single register has
+    ($name:ident[$count:expr], $addr:expr, stride: $stride:expr $(, access: $access:ident)?) => {
+        register!($name[$count], $addr, stride: $stride $(, access: $access)?, []);
+    };
 
-            #[inline(always)]
-            pub unsafe fn new_scope<F: FnOnce(&mut Value)>(f: F) {
-                unsafe { let mut v = Value::new(0); f(&mut v); write(v); }
-            }
+    ($name:ident[$count:expr], $addr:expr, stride: $stride:expr, [ $($fields:tt)* ]) => {
+        register!($name[$count], $addr, stride: $stride, access: rw, [ $($fields)* ]);
+    };
 
-            #[inline(always)]
-            pub unsafe fn read_modify_write<F: FnOnce(&mut Value)>(f: F) {
-                unsafe { let mut v = read(); f(&mut v); write(v); }
-            }
+    ($name:ident[$count:expr], $addr:expr, stride: $stride:expr, access: $access:ident, [ $( $kind:ident: $field:ident, offset: $shift:expr $(, width: $width:expr)? $(, values: $values:ty)? $(, access: $faccess:ident)? );* $(;)? ]) => {
+        pub mod $name {
+            #[allow(unused_imports)]
+            use super::*;
 
-            #[inline(always)]
-            pub unsafe fn read_raw() -> usize { unsafe { REG.read() } }
+            pub struct TypeLock;
+            pub const COUNT: usize = $count;
 
-            #[inline(always)]
-            pub unsafe fn write_raw(val: usize) {
-                unsafe { REG.write(val) };
-            }
+            $(
+                register!(@item $kind $field, $shift, [$($width)?], [$($values)?], [$($faccess)?]);
+            )*
+        }
+
+        #[inline(always)]
+        pub const fn $name(
+            n: usize,
+        ) -> crate::drivers::regs::Register<$name::TypeLock, register!(@access $access)> {
+            assert!(n < $name::COUNT);
+            crate::drivers::regs::Register::new($addr + n * $stride)
         }
     };
 
-    (@item field $field:ident, $shift:expr, $width:expr) => {
-        pub const $field: crate::drivers::regs::Field<TypeLock> = crate::drivers::regs::Field::new($shift, $width);
+    (@access rw) => { crate::drivers::regs::ReadWrite };
+    (@access ro) => { crate::drivers::regs::ReadOnly };
+    (@access wo) => { crate::drivers::regs::WriteOnly };
+    (@access w1c) => { crate::drivers::regs::WriteOneToClear };
+    // Fields default to read/write
+    (@access) => { crate::drivers::regs::ReadWrite };
+
+    (@item field $field:ident, $shift:expr, [$width:expr], [], [$($access:ident)?]) => {
+        pub const $field: crate::drivers::regs::Field<TypeLock, register!(@access $($access)?)> =
+            crate::drivers::regs::Field::new($shift, $width);
+    };
+    (@item field $field:ident, $shift:expr, [$width:expr], [$values:ty], [$($access:ident)?]) => {
+        pub const $field: crate::drivers::regs::EnumField<TypeLock, $values, register!(@access $($access)?)> =
+            crate::drivers::regs::EnumField::new($shift, $width);
+    };
+    (@item bit $field:ident, $shift:expr, [$($width:expr)?], [], [$($access:ident)?]) => {
+        pub const $field: crate::drivers::regs::Bit<TypeLock, register!(@access $($access)?)> =
+            crate::drivers::regs::Bit::new($shift);
+    };
+
+    (@raw_fns rw) => { register!(@raw_read); register!(@raw_write); };
+    (@raw_fns ro) => { register!(@raw_read); };
+    (@raw_fns wo) => { register!(@raw_write); };
+    (@raw_fns w1c) => { register!(@raw_read); register!(@raw_write); };
+
+    (@raw_read) => {
+        pub unsafe fn read() -> usize { unsafe { REG.read_raw() } }
     };
-    (@item bit $field:ident, $shift:expr $(, $width:expr)?) => {
-        pub const $field: crate::drivers::regs::Bit<TypeLock> = crate::drivers::regs::Bit::new($shift);
+    (@raw_write) => {
+        pub unsafe fn write(val: usize) { unsafe { REG.write_raw(val) } }
+    };
+
+    (@fns rw) => { register!(@read); register!(@write); register!(@modify); };
+    (@fns ro) => { register!(@read); };
+    (@fns wo) => { register!(@write); };
+    (@fns w1c) => { register!(@read); register!(@write); };
+
+    (@read) => {
+        #[inline(always)]
+        pub unsafe fn read() -> Value {
+            unsafe { REG.read() }
+        }
+
+        #[inline(always)]
+        pub unsafe fn read_raw() -> usize { unsafe { REG.read_raw() } }
+    };
+    (@write) => {
+        #[inline(always)]
+        pub unsafe fn write(val: Value) {
+            unsafe { REG.write(val) }
+        }
+
+        #[inline(always)]
+        pub unsafe fn new_scope<F: FnOnce(&mut Value)>(f: F) {
+            unsafe { REG.new_scope(f) }
+        }
+
+        #[inline(always)]
+        pub unsafe fn write_raw(val: usize) {
+            unsafe { REG.write_raw(val) };
+        }
+    };
+    (@modify) => {
+        #[inline(always)]
+        pub unsafe fn read_modify_write<F: FnOnce(&mut Value)>(f: F) {
+            unsafe { REG.read_modify_write(f) }
+        }
+    };
+}
+
+/// Declares the legal values of an enumerated register field, to be named in
+/// `register!` as `field: NAME, offset: n, width: w, values: Type;`.
+macro_rules! field_values {
+    ($(#[$meta:meta])* $vis:vis enum $name:ident { $( $(#[$vmeta:meta])* $variant:ident = $value:expr ),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, PartialEq, Eq)]
+        $vis enum $name {
+            $( $(#[$vmeta])* $variant = $value ),*
+        }
+
+        impl crate::drivers::regs::FieldValue for $name {
+            fn from_bits(bits: usize) -> Option<Self> {
+                match bits {
+                    $( x if x == $value => Some(Self::$variant), )*
+                    _ => None,
+                }
+            }
+
+            fn bits(self) -> usize {
+                self as usize
+            }
+        }
     };
 }
 
+pub(super) use field_values;
 pub(super) use register;
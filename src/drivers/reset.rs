@@ -0,0 +1,112 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use ufmt::{uDisplay, uwrite};
+
+use crate::drivers::{bit, clk::pll::TOPCRM_BASE, readl, watchdog, writel};
+
+// Offset and bits are still to be confirmed on hardware
+const TOPCRM_RESET_STATUS: usize = TOPCRM_BASE + 0x0f0;
+const FLAG_WATCHDOG: usize = bit(0);
+const FLAG_SOFTWARE: usize = bit(1);
+const FLAG_A53_REQUEST: usize = bit(2);
+const FLAGS: usize = FLAG_WATCHDOG | FLAG_SOFTWARE | FLAG_A53_REQUEST;
+
+/// Set by `detect`, zero before
+static DETECTED: AtomicU8 = AtomicU8::new(0);
+
+/// Why the SoC came out of reset. `Watchdog::reset` shows up as a watchdog
+/// reset, not a software one.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum ResetReason {
+    PowerOn = 1,
+    Watchdog = 2,
+    Software = 3,
+    A53Request = 4,
+}
+
+impl ResetReason {
+    /// Reads the status, so only call it once per boot. Later callers use
+    /// `last`. The flags are only cleared along with the `watchdog` feature,
+    /// until then the register is left alone.
+    pub unsafe fn detect() -> Self {
+        let status = unsafe { readl(TOPCRM_RESET_STATUS) };
+        if watchdog::ENABLED {
+            unsafe { writel(TOPCRM_RESET_STATUS, status & FLAGS) };
+        }
+
+        // More than one may be set, the watchdog is the one to know about
+        let reason = if status & FLAG_WATCHDOG != 0 {
+            Self::Watchdog
+        } else if status & FLAG_A53_REQUEST != 0 {
+            Self::A53Request
+        } else if status & FLAG_SOFTWARE != 0 {
+            Self::Software
+        } else {
+            Self::PowerOn
+        };
+
+        DETECTED.store(reason as u8, Ordering::Relaxed);
+
+        reason
+    }
+
+    /// What `detect` found, `None` if it hasn't run.
+    pub fn last() -> Option<Self> {
+        match DETECTED.load(Ordering::Relaxed) {
+            1 => Some(Self::PowerOn),
+            2 => Some(Self::Watchdog),
+            3 => Some(Self::Software),
+            4 => Some(Self::A53Request),
+            _ => None,
+        }
+    }
+}
+
+impl uDisplay for ResetReason {
+    fn fmt<W>(&self, f: &mut ufmt::Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: ufmt::uWrite + ?Sized,
+    {
+        match self {
+            Self::PowerOn => uwrite!(f, "power-on"),
+            Self::Watchdog => uwrite!(f, "watchdog"),
+            Self::Software => uwrite!(f, "software"),
+            Self::A53Request => uwrite!(f, "A53 request"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::mmio::sim;
+
+    fn detect(status: usize) -> ResetReason {
+        sim::reset();
+        sim::set(TOPCRM_RESET_STATUS, status);
+
+        unsafe { ResetReason::detect() }
+    }
+
+    #[test]
+    fn decodes_reset_status() {
+        assert!(matches!(detect(0), ResetReason::PowerOn));
+        assert!(matches!(detect(FLAG_SOFTWARE), ResetReason::Software));
+        assert!(matches!(detect(FLAG_A53_REQUEST), ResetReason::A53Request));
+        assert!(matches!(detect(FLAGS), ResetReason::Watchdog));
+    }
+
+    #[test]
+    fn acks_only_known_flags() {
+        let status = FLAG_WATCHDOG | bit(31);
+        detect(status);
+
+        let expected = if watchdog::ENABLED {
+            FLAG_WATCHDOG
+        } else {
+            status
+        };
+        assert_eq!(sim::get(TOPCRM_RESET_STATUS), expected);
+    }
+}
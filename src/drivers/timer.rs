@@ -0,0 +1,158 @@
+use core::cell::UnsafeCell;
+
+use crate::drivers::clk::soc::SoCClocks;
+#[cfg(feature = "interrupts")]
+use crate::drivers::nvic::{Exception, Nvic, critical_section};
+use crate::drivers::{StatelessDriver, bit, readl, writel};
+
+pub(super) const SYST_BASE: usize = 0xe000e010;
+const SYST_CSR: usize = SYST_BASE;
+const SYST_RVR: usize = SYST_BASE + 0x4;
+pub(super) const SYST_CVR: usize = SYST_BASE + 0x8;
+#[cfg(feature = "mmio-trace")]
+pub(super) const SYST_END: usize = SYST_BASE + 0x10;
+
+const FLAG_ENABLE: usize = bit(0);
+const FLAG_TICK_INT: usize = bit(1);
+const FLAG_CPU_CLOCK: usize = bit(2);
+/// Set when the counter wrapped, cleared by reading `SYST_CSR`
+const FLAG_COUNTED: usize = bit(16);
+const COUNTER_MASK: usize = 0xffffff;
+
+struct State {
+    /// Ticks per second
+    rate: u64,
+    last: usize,
+    /// Time up to the last rate change
+    base_us: u64,
+    /// Ticks since the last rate change
+    ticks: u64,
+}
+
+struct StateCell(UnsafeCell<State>);
+
+unsafe impl Sync for StateCell {}
+
+static STATE: StateCell = StateCell(UnsafeCell::new(State {
+    rate: 1,
+    last: 0,
+    base_us: 0,
+    ticks: 0,
+}));
+
+/// SysTick as a free running time base. The counter is only 24 bits wide,
+/// wraps are accounted for whenever the time is read, so that has to happen
+/// at least once per wrap (0.6 s at 26 MHz). Anything waiting on the time
+/// does so in its loop anyway. With `interrupts` every wrap also raises
+/// SysTick, which keeps the time across a long `wfi` and wakes it up.
+///
+/// Not to be used from interrupt handlers.
+pub struct Timer;
+
+impl StatelessDriver for Timer {
+    /// The rate is taken from the M0's clock mux, `recalibrate` has to be
+    /// called when that changes.
+    unsafe fn init() -> Self {
+        unsafe {
+            writel(SYST_CSR, 0);
+            writel(SYST_RVR, COUNTER_MASK);
+            writel(SYST_CVR, 0);
+
+            #[cfg(feature = "interrupts")]
+            {
+                Nvic::register_exception(Exception::SysTick, Self::on_wrap);
+                writel(SYST_CSR, FLAG_ENABLE | FLAG_TICK_INT | FLAG_CPU_CLOCK);
+            }
+            #[cfg(not(feature = "interrupts"))]
+            writel(SYST_CSR, FLAG_ENABLE | FLAG_CPU_CLOCK);
+
+            let state = &mut *STATE.0.get();
+            state.rate = SoCClocks::m0_rate() as u64;
+            state.last = readl(SYST_CVR) & COUNTER_MASK;
+        }
+
+        Self
+    }
+}
+
+impl Timer {
+    /// Picks up a new M0 clock rate, time keeps counting from where it was.
+    pub unsafe fn recalibrate() {
+        let now = Self::micros();
+
+        let state = unsafe { &mut *STATE.0.get() };
+        state.base_us = now;
+        state.ticks = 0;
+        state.rate = unsafe { SoCClocks::m0_rate() } as u64;
+    }
+
+    /// Monotonic time since `init`.
+    pub fn micros() -> u64 {
+        #[cfg(feature = "interrupts")]
+        return critical_section(Self::update);
+        #[cfg(not(feature = "interrupts"))]
+        Self::update()
+    }
+
+    fn update() -> u64 {
+        let state = unsafe { &mut *STATE.0.get() };
+        let now = unsafe { readl(SYST_CVR) } & COUNTER_MASK;
+
+        // SysTick counts down, so it wrapped if it went up. Counted here, so
+        // `on_wrap` mustn't count it again.
+        if cfg!(feature = "interrupts") && now > state.last {
+            unsafe { readl(SYST_CSR) };
+        }
+
+        state.ticks += (state.last.wrapping_sub(now) & COUNTER_MASK) as u64;
+        state.last = now;
+
+        state.base_us + state.ticks * 1_000_000 / state.rate
+    }
+
+    /// Counts the wrap up to the top of the counter, unless `micros` saw it
+    /// first. Runs with `micros` masked, so it can't interleave with it.
+    #[cfg(feature = "interrupts")]
+    fn on_wrap() {
+        let state = unsafe { &mut *STATE.0.get() };
+
+        if unsafe { readl(SYST_CSR) } & FLAG_COUNTED != 0 {
+            state.ticks += state.last as u64 + 1;
+            state.last = COUNTER_MASK;
+        }
+    }
+}
+
+pub struct TimedOut;
+
+/// Point in time a wait gives up at.
+#[derive(Clone, Copy)]
+pub struct Deadline(u64);
+
+impl Deadline {
+    pub fn after_us(us: u64) -> Self {
+        Self(Timer::micros() + us)
+    }
+
+    pub fn after_ms(ms: u64) -> Self {
+        Self::after_us(ms * 1000)
+    }
+
+    pub fn expired(&self) -> bool {
+        Timer::micros() >= self.0
+    }
+
+    /// Polls `done` until it returns `true`, callers map running out of time
+    /// to their own error.
+    pub fn wait(&self, mut done: impl FnMut() -> bool) -> Result<(), TimedOut> {
+        loop {
+            if done() {
+                break Ok(());
+            }
+
+            if self.expired() {
+                break Err(TimedOut);
+            }
+        }
+    }
+}
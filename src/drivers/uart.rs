@@ -1,22 +1,40 @@
+#[cfg(feature = "interrupts")]
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use simpleport::{SimpleRead, SimpleWrite};
 use ufmt::uWrite;
 
+use crate::drivers::clk::soc::SoCClocks;
+#[cfg(feature = "interrupts")]
+use crate::drivers::nvic::{Irq, Nvic, critical_section};
+use crate::drivers::timer::Deadline;
 use crate::drivers::{StatelessDriver, bit, readl, writel};
+use crate::err::UARTError;
+use crate::ring::RingBuffer;
+
+pub const UART1_BASE: usize = 0x01408000;
 
-const UART_CLOCK: usize = 26000000;
-const UART_BAUD: usize = 115200;
+// Offsets from the instance base
+const UART_DR: usize = 0x04;
+const UART_FR: usize = 0x14;
+const UART_IBRD: usize = 0x24;
+const UART_FBRD: usize = 0x28;
+const UART_LCR: usize = 0x30;
+const UART_CR: usize = 0x34;
+const UART_IMSC: usize = 0x40;
+const UART_ICR: usize = 0x4c;
 
-const UART1_BASE: usize = 0x01408000;
-const UART_DR: usize = UART1_BASE + 0x04;
-const UART_FR: usize = UART1_BASE + 0x14;
-const UART_IBRD: usize = UART1_BASE + 0x24;
-const UART_FBRD: usize = UART1_BASE + 0x28;
-const UART_LCR: usize = UART1_BASE + 0x30;
-const UART_CR: usize = UART1_BASE + 0x34;
-const UART_IMSC: usize = UART1_BASE + 0x40;
-const UART_ICR: usize = UART1_BASE + 0x4c;
+const FBRD_BITS: usize = 6;
+const IBRD_MAX: usize = 0xffff;
+/// Past this the receiving end starts sampling the wrong bits
+const MAX_BAUD_ERROR_PERCENT: usize = 2;
+/// How long to wait for room in the TX ring, or for it to drain
+const TX_TIMEOUT_US: u64 = 100_000;
 
 const FLAG_ENABLE: usize = bit(0);
 const FLAG_TX_ENABLE: usize = bit(8);
+const FLAG_RX_ENABLE: usize = bit(9);
 
 const FLAG_BREAK: usize = bit(0);
 const FLAG_PARITY: usize = bit(1);
@@ -24,16 +42,108 @@ const FLAG_TWO_STOP_BITS: usize = bit(3);
 const FLAG_FIFO: usize = bit(4);
 const FLAG_TX_8BITS: usize = 3 << 5;
 
+const FLAG_RX_EMPTY: usize = bit(4);
+const FLAG_TX_FULL: usize = bit(5);
 const FLAG_BUSY: usize = bit(8);
 
+// Status bits latched alongside each byte in UART_DR, as on the PL011
+const FLAG_FRAMING_ERR: usize = bit(8);
+const FLAG_PARITY_ERR: usize = bit(9);
+const FLAG_BREAK_ERR: usize = bit(10);
+const FLAG_OVERRUN_ERR: usize = bit(11);
+
+#[cfg(feature = "interrupts")]
+const FLAG_RX_INTR: usize = bit(4);
+#[cfg(feature = "interrupts")]
+const FLAG_TX_INTR: usize = bit(5);
+#[cfg(feature = "interrupts")]
+const FLAG_RX_TIMEOUT_INTR: usize = bit(6);
+
+/// Output waiting for room in the TX FIFO, so printing doesn't stall
+/// whatever is being logged about.
+static TX_RING: RingBuffer<512> = RingBuffer::new();
+/// Set once the UART stopped taking data, output is dropped without waiting
+/// from then on until it takes a byte again.
+static TX_STUCK: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "interrupts")]
+static RX_RING: RingBuffer<256> = RingBuffer::new();
+/// First error seen by the interrupt handler since the last `try_getc`,
+/// `RX_OK` if none.
+#[cfg(feature = "interrupts")]
+static RX_ERROR: AtomicU8 = AtomicU8::new(RX_OK);
+#[cfg(feature = "interrupts")]
+const RX_OK: u8 = u8::MAX;
+
+/// Instance `Serial` talks to, the boot ROM leaves UART1 set up for us.
+static CONSOLE_BASE: AtomicUsize = AtomicUsize::new(UART1_BASE);
+
+#[derive(Clone, Copy)]
+pub struct UartConfig {
+    pub base: usize,
+    pub baud: usize,
+}
+
+impl UartConfig {
+    pub const DEFAULT: Self = Self {
+        base: UART1_BASE,
+        baud: 115200,
+    };
+
+    /// Integer and fractional baud rate divisors for an input clock of
+    /// `clock` Hz, the UART oversamples 16x and takes the fraction in 64ths.
+    pub const fn divisors(&self, clock: usize) -> Result<(usize, usize), UARTError> {
+        // clock * 64 / (16 * baud), rounded to nearest
+        let div = match clock.checked_mul(4) {
+            Some(clock) => match (clock + self.baud / 2).checked_div(self.baud) {
+                Some(div) => div,
+                None => return Err(UARTError::UnreachableBaud),
+            },
+            None => return Err(UARTError::UnreachableBaud),
+        };
+
+        let ibrd = div >> FBRD_BITS;
+        let fbrd = div & ((1 << FBRD_BITS) - 1);
+        if ibrd == 0 || ibrd > IBRD_MAX || (ibrd == IBRD_MAX && fbrd != 0) {
+            return Err(UARTError::UnreachableBaud);
+        }
+
+        let actual = clock * 4 / div;
+        if actual.abs_diff(self.baud) * 100 > self.baud * MAX_BAUD_ERROR_PERCENT {
+            return Err(UARTError::UnreachableBaud);
+        }
+
+        Ok((ibrd, fbrd))
+    }
+}
+
 pub struct Serial;
 
+#[inline(always)]
+fn reg(offset: usize) -> usize {
+    CONSOLE_BASE.load(Ordering::Relaxed) + offset
+}
+
+/// Data and flag registers of the console, polled for every byte.
+#[cfg(any(test, feature = "mmio-trace"))]
+pub(super) fn console_regs() -> [usize; 2] {
+    [reg(UART_DR), reg(UART_FR)]
+}
+
 impl Serial {
+    /// Drops the byte if the UART stopped taking data, a stuck console isn't
+    /// worth hanging the boot over. Only the first byte that finds the ring
+    /// full waits for it.
     fn raw_putc(c: u8) {
-        unsafe {
-            while Self::busy() {}
-            writel(UART_DR, c as usize);
-        };
+        if !TX_RING.push(c) && !TX_STUCK.load(Ordering::Relaxed) {
+            let deadline = Deadline::after_us(TX_TIMEOUT_US);
+            let result = deadline.wait(|| {
+                Self::poll();
+                TX_RING.push(c)
+            });
+            TX_STUCK.store(result.is_err(), Ordering::Relaxed);
+        }
+        Self::poll();
     }
 
     pub fn putc(c: u8) {
@@ -45,17 +155,203 @@ impl Serial {
 
     #[inline(always)]
     unsafe fn busy() -> bool {
-        (unsafe { readl(UART_FR) } & FLAG_BUSY) != 0
+        (unsafe { readl(reg(UART_FR)) } & FLAG_BUSY) != 0
+    }
+
+    /// Moves queued output into the TX FIFO. Cheap enough to call from any
+    /// loop that would otherwise just spin.
+    pub fn poll() {
+        #[cfg(feature = "interrupts")]
+        critical_section(|| unsafe { Self::drain() });
+        #[cfg(not(feature = "interrupts"))]
+        unsafe {
+            Self::drain()
+        };
+    }
+
+    /// Waits until everything queued has left the UART, the AP or whoever
+    /// takes over the console next doesn't know about our buffer.
+    pub fn flush() -> Result<(), UARTError> {
+        Self::poll();
+        if TX_STUCK.load(Ordering::Relaxed) {
+            return Err(UARTError::Timeout);
+        }
+
+        let deadline = Deadline::after_us(TX_TIMEOUT_US);
+
+        deadline
+            .wait(|| {
+                Self::poll();
+                TX_RING.is_empty() && unsafe { !Self::busy() }
+            })
+            .map_err(|_| {
+                TX_STUCK.store(true, Ordering::Relaxed);
+                UARTError::Timeout
+            })
+    }
+
+    unsafe fn drain() {
+        unsafe {
+            while readl(reg(UART_FR)) & FLAG_TX_FULL == 0 {
+                let Some(c) = TX_RING.pop() else {
+                    break;
+                };
+                writel(reg(UART_DR), c as usize);
+                TX_STUCK.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn rx_empty() -> bool {
+        (unsafe { readl(reg(UART_FR)) } & FLAG_RX_EMPTY) != 0
+    }
+
+    /// Pops one entry off the RX FIFO, the error bits come with the byte
+    /// they were latched for.
+    unsafe fn rx_byte() -> Result<u8, UARTError> {
+        let dr = unsafe { readl(reg(UART_DR)) };
+
+        if dr & FLAG_OVERRUN_ERR != 0 {
+            Err(UARTError::Overrun)
+        } else if dr & FLAG_BREAK_ERR != 0 {
+            Err(UARTError::Break)
+        } else if dr & FLAG_FRAMING_ERR != 0 {
+            Err(UARTError::Framing)
+        } else if dr & FLAG_PARITY_ERR != 0 {
+            Err(UARTError::Parity)
+        } else {
+            Ok(dr as u8)
+        }
+    }
+
+    /// Returns the next received byte, `None` if nothing is pending.
+    #[cfg(not(feature = "interrupts"))]
+    pub fn try_getc() -> Result<Option<u8>, UARTError> {
+        unsafe {
+            if Self::rx_empty() {
+                return Ok(None);
+            }
+
+            Self::rx_byte().map(Some)
+        }
+    }
+
+    /// Returns the next received byte, `None` if nothing is pending.
+    #[cfg(feature = "interrupts")]
+    pub fn try_getc() -> Result<Option<u8>, UARTError> {
+        let err = RX_ERROR.load(Ordering::Acquire);
+        if err != RX_OK {
+            RX_ERROR.store(RX_OK, Ordering::Release);
+            return Err(match err {
+                0 => UARTError::Framing,
+                1 => UARTError::Parity,
+                2 => UARTError::Break,
+                _ => UARTError::Overrun,
+            });
+        }
+
+        Ok(RX_RING.pop())
+    }
+
+    /// Waits for a byte, giving up after `timeout_ms` if one is given.
+    pub fn getc(timeout_ms: Option<u64>) -> Result<u8, UARTError> {
+        let deadline = timeout_ms.map(Deadline::after_ms);
+
+        loop {
+            if let Some(c) = Self::try_getc()? {
+                break Ok(c);
+            }
+            Self::poll();
+
+            if deadline.is_some_and(|deadline| deadline.expired()) {
+                break Err(UARTError::Timeout);
+            }
+        }
+    }
+
+    /// Switches reception over to the UART1 interrupt, bytes are queued
+    /// until `getc` picks them up, and lets the TX FIFO running low refill it
+    /// from our buffer. Other instances aren't wired up in the NVIC, the
+    /// console has to be on UART1 for this.
+    #[cfg(feature = "interrupts")]
+    pub unsafe fn enable_interrupts() {
+        unsafe {
+            writel(
+                reg(UART_IMSC),
+                FLAG_RX_INTR | FLAG_RX_TIMEOUT_INTR | FLAG_TX_INTR,
+            );
+            Nvic::register(Irq::Uart1, Self::irq_handler);
+            Nvic::enable(Irq::Uart1);
+        }
+    }
+
+    #[cfg(feature = "interrupts")]
+    fn irq_handler() {
+        unsafe {
+            while !Self::rx_empty() {
+                let err = match Self::rx_byte() {
+                    Ok(c) if RX_RING.push(c) => continue,
+                    Ok(_) => UARTError::Overrun,
+                    Err(err) => err,
+                };
+
+                if RX_ERROR.load(Ordering::Relaxed) == RX_OK {
+                    RX_ERROR.store(err as u8, Ordering::Release);
+                }
+            }
+
+            // With nothing left to send the TX interrupt would keep firing,
+            // the next `poll` restarts the FIFO
+            Self::drain();
+
+            writel(
+                reg(UART_ICR),
+                FLAG_RX_INTR | FLAG_RX_TIMEOUT_INTR | FLAG_TX_INTR,
+            );
+        }
     }
 
-    unsafe fn setbrg() {
-        const IBRD: usize = UART_CLOCK / (UART_BAUD << 4);
-        const FBRD: usize =
-            (((UART_CLOCK - ((IBRD * UART_BAUD) << 4)) << 6) + (UART_BAUD << 3)) / (UART_BAUD << 4);
+    /// Moves the console to another instance or baud rate, the old settings
+    /// stay in place if the rate can't be reached. The divisors are worked
+    /// out from the clock rate `SoCClocks` reports at the time.
+    pub unsafe fn configure(config: &UartConfig) -> Result<(), UARTError> {
+        let divisors = config.divisors(SoCClocks::uart_rate())?;
+        unsafe { Self::setup(config.base, divisors) };
+
+        Ok(())
+    }
 
+    unsafe fn setup(base: usize, (ibrd, fbrd): (usize, usize)) {
         unsafe {
-            writel(UART_IBRD, IBRD);
-            writel(UART_FBRD, FBRD);
+            // Let the old console finish what it's sending
+            let _ = Self::flush();
+            CONSOLE_BASE.store(base, Ordering::Relaxed);
+
+            // Stop
+            writel(base + UART_ICR, 0xffff);
+            writel(
+                base + UART_CR,
+                !(FLAG_ENABLE | FLAG_TX_ENABLE | FLAG_RX_ENABLE),
+            );
+
+            // Set baud
+            writel(base + UART_IBRD, ibrd);
+            writel(base + UART_FBRD, fbrd);
+
+            writel(
+                base + UART_LCR,
+                readl(base + UART_LCR) & !(FLAG_BREAK | FLAG_PARITY | FLAG_TWO_STOP_BITS),
+            );
+            writel(base + UART_LCR, FLAG_FIFO | FLAG_TX_8BITS);
+
+            // Disable interrupts
+            writel(base + UART_IMSC, 0);
+
+            writel(
+                base + UART_CR,
+                FLAG_ENABLE | FLAG_TX_ENABLE | FLAG_RX_ENABLE,
+            );
         }
     }
 }
@@ -71,30 +367,105 @@ impl uWrite for Serial {
     }
 }
 
+impl SimpleRead for Serial {
+    type Error = UARTError;
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
+        for b in buf {
+            *b = Self::getc(None)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Raw bytes for binary protocols, unlike `uWrite` newlines go out as is.
+impl SimpleWrite for Serial {
+    type Error = core::convert::Infallible;
+
+    fn write(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        for b in buf {
+            Self::raw_putc(*b);
+        }
+
+        Ok(())
+    }
+}
+
 impl StatelessDriver for Serial {
+    /// Has to run again whenever the clock setup changes, until then the
+    /// ROM's divisors are used.
     unsafe fn init() -> Self {
-        unsafe {
-            while Self::busy() {}
+        // Left as the ROM set it up if the clock can't do the default rate
+        let _ = unsafe { Self::configure(&UartConfig::DEFAULT) };
 
-            // Stop
-            writel(UART_ICR, 0xffff);
-            writel(UART_CR, !(FLAG_ENABLE | FLAG_TX_ENABLE));
+        Self
+    }
+}
 
-            // Set baud
-            Self::setbrg();
+/// Has the console receive `input`, for host tests of whatever reads it. The
+/// RX FIFO reads empty once it is used up.
+#[cfg(test)]
+pub fn feed(input: &[u8]) {
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc};
 
-            writel(
-                UART_LCR,
-                readl(UART_LCR) & !(FLAG_BREAK | FLAG_PARITY | FLAG_TWO_STOP_BITS),
-            );
-            writel(UART_LCR, FLAG_FIFO | FLAG_TX_8BITS);
+    use crate::drivers::mmio::sim;
 
-            // Disable interrupts
-            writel(UART_IMSC, 0);
+    let rx = Rc::new(RefCell::new(VecDeque::from(input.to_vec())));
+    let fr = rx.clone();
+
+    sim::reset();
+    sim::on_read(reg(UART_FR), move |_| {
+        if fr.borrow().is_empty() {
+            FLAG_RX_EMPTY
+        } else {
+            0
+        }
+    });
+    sim::on_read(reg(UART_DR), move |_| {
+        rx.borrow_mut().pop_front().unwrap_or_default() as usize
+    });
+}
 
-            writel(UART_CR, FLAG_ENABLE | FLAG_TX_ENABLE);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::mmio::sim;
 
-            Self
+    fn divisors(baud: usize) -> Result<(usize, usize), UARTError> {
+        UartConfig {
+            baud,
+            ..UartConfig::DEFAULT
         }
+        .divisors(SoCClocks::uart_rate())
+    }
+
+    #[test]
+    fn computes_divisors() {
+        assert!(matches!(divisors(115200), Ok((14, 7))));
+        assert!(matches!(divisors(921600), Ok((1, 49))));
+    }
+
+    #[test]
+    fn getc_times_out() {
+        feed(b"x");
+
+        assert!(matches!(Serial::getc(Some(10)), Ok(b'x')));
+        assert!(matches!(Serial::getc(Some(10)), Err(UARTError::Timeout)));
+    }
+
+    #[test]
+    fn flush_times_out_on_busy_uart() {
+        sim::reset();
+        sim::set(reg(UART_FR), FLAG_BUSY);
+
+        assert!(matches!(Serial::flush(), Err(UARTError::Timeout)));
+    }
+
+    #[test]
+    fn rejects_unreachable_baud() {
+        assert!(matches!(divisors(0), Err(UARTError::UnreachableBaud)));
+        assert!(matches!(divisors(4000000), Err(UARTError::UnreachableBaud)));
+        assert!(matches!(divisors(20), Err(UARTError::UnreachableBaud)));
     }
 }
@@ -1,77 +1,402 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use simpleport::{SimpleRead, SimpleWrite};
-use ufmt::uwriteln;
 
 use crate::drivers::DriverMut;
-use crate::drivers::regs::register;
-use crate::drivers::uart::Serial;
+#[cfg(feature = "interrupts")]
+use crate::drivers::bit;
+use crate::drivers::delay::mdelay;
+#[cfg(feature = "interrupts")]
+use crate::drivers::nvic::{Irq, Nvic, critical_section, wfi};
+use crate::drivers::regs::{field_values, register};
+use crate::drivers::timer::Deadline;
+use crate::drivers::watchdog::Watchdog;
 use crate::err::USBError;
+use crate::log::{debug, info, warn};
+use crate::timing::Phase;
+
+const WRITE_TIMEOUT_US: u64 = 2_000_000;
+/// How long the host may go quiet mid-command
+const READ_TIMEOUT_US: u64 = 5_000_000;
+/// A FIFO flush takes a few PHY clocks
+const FLUSH_TIMEOUT_US: u64 = 10_000;
+/// Long enough for the host to notice we left
+const RECONNECT_DELAY_MS: usize = 50;
+/// Sent in place of whatever reply the host waits for when we panic, no
+/// protocol uses it for anything else
+const PANIC_STATUS: u8 = 0xee;
+
+/// Set once we took the controller over from the ROM
+static SESSION: AtomicBool = AtomicBool::new(false);
 
-const TYPE_BULK: usize = 2;
+const DESC_DEVICE: u8 = 1;
+const DESC_CONFIG: u8 = 2;
+const DESC_STRING: u8 = 3;
+const DESC_QUALIFIER: u8 = 6;
+
+const REQ_GET_STATUS: u8 = 0x00;
+const REQ_CLEAR_FEATURE: u8 = 0x01;
+const REQ_SET_ADDRESS: u8 = 0x05;
+const REQ_GET_DESCRIPTOR: u8 = 0x06;
+const REQ_GET_CONFIGURATION: u8 = 0x08;
+const REQ_SET_CONFIGURATION: u8 = 0x09;
+const REQ_SET_INTERFACE: u8 = 0x0b;
+
+const FEATURE_ENDPOINT_HALT: u8 = 0;
+const ENDPOINT_DIR_IN: u8 = 0x80;
 
 const USB_BASE: usize = 0x01500000;
 
+// Shared by every OUT endpoint and SETUP packets: two max sized packets plus
+// room for SETUPs and transfer complete entries
+const RX_FIFO_WORDS: usize = 272;
+// EP0 IN, one 64 byte control packet
+const EP0_TX_FIFO_WORDS: usize = 16;
+
+const TXFNUM_ALL: usize = 0x10;
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+field_values! {
+    pub enum EndpointType {
+        Control = 0,
+        Isochronous = 1,
+        Bulk = 2,
+        Interrupt = 3,
+    }
+}
+
+field_values! {
+    pub enum PacketStatus {
+        GlobalOutNak = 1,
+        OutReceived = 2,
+        OutComplete = 3,
+        SetupComplete = 4,
+        SetupReceived = 6,
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Endpoint {
+    pub number: usize,
+    pub direction: Direction,
+    pub kind: EndpointType,
+    /// High speed max packet size, full speed caps it at 64
+    pub mps: usize,
+    /// Dedicated TX FIFO depth in 32-bit words, IN endpoints only
+    pub tx_fifo_words: usize,
+}
+
+impl Endpoint {
+    const fn is_in(&self) -> bool {
+        matches!(self.direction, Direction::In)
+    }
+}
+
+pub const ENDPOINTS: [Endpoint; 2] = [
+    Endpoint {
+        number: 1,
+        direction: Direction::In,
+        kind: EndpointType::Bulk,
+        mps: 512,
+        tx_fifo_words: 256,
+    },
+    Endpoint {
+        number: 1,
+        direction: Direction::Out,
+        kind: EndpointType::Bulk,
+        mps: 512,
+        tx_fifo_words: 0,
+    },
+];
+
+const fn find_endpoint(number: usize, is_in: bool) -> Endpoint {
+    let mut i = 0;
+    while i < ENDPOINTS.len() {
+        if ENDPOINTS[i].number == number && ENDPOINTS[i].is_in() == is_in {
+            return ENDPOINTS[i];
+        }
+        i += 1;
+    }
+    panic!("endpoint missing from ENDPOINTS");
+}
+
+const BULK_IN: Endpoint = find_endpoint(1, true);
+const BULK_OUT: Endpoint = find_endpoint(1, false);
+
+const _: () = {
+    let mut i = 0;
+    while i < ENDPOINTS.len() {
+        if ENDPOINTS[i].number == 0 || ENDPOINTS[i].number >= 16 {
+            panic!("endpoint numbers must be within 1-15");
+        }
+        i += 1;
+    }
+
+    if BULK_OUT.mps > 512 {
+        panic!("OUT packets must fit the receive buffer");
+    }
+};
+
+register!(gotgint, USB_BASE + 0x004, access: w1c, [
+    bit: SESENDDET, offset: 2;
+]);
+
+register!(grstctl, USB_BASE + 0x010, [
+    bit: RXFFLSH, offset: 4;
+    bit: TXFFLSH, offset: 5;
+    field: TXFNUM, offset: 6, width: 5;
+]);
+
 register!(gahbcfg, USB_BASE + 0x008, [
+    bit: GLBLINTRMSK, offset: 0;
     bit: NPTXFEMPLVL, offset: 5, width: 1;
 ]);
 
-register!(gintsts, USB_BASE + 0x014, [
+register!(gintsts, USB_BASE + 0x014, access: w1c, [
+    // Summary bits, cleared at their source
+    bit: OTGINT, offset: 2, access: ro;
+    bit: RXFLVL, offset: 4, access: ro;
+    bit: USBSUSP, offset: 11;
+    bit: USBRST, offset: 12;
+    bit: ENUMDONE, offset: 13;
+    bit: OEPINT, offset: 19, access: ro;
+    bit: WKUPINT, offset: 31;
+]);
+
+register!(gintmsk, USB_BASE + 0x018, [
+    bit: OTGINT, offset: 2;
     bit: RXFLVL, offset: 4;
+    bit: USBSUSP, offset: 11;
+    bit: USBRST, offset: 12;
+    bit: ENUMDONE, offset: 13;
     bit: OEPINT, offset: 19;
+    bit: WKUPINT, offset: 31;
+]);
+
+// Reading pops the entry
+register!(grxstsp, USB_BASE + 0x020, access: ro, [
+    field: EPNUM, offset: 0, width: 4;
+    field: BCNT, offset: 4, width: 11;
+    field: PKTSTS, offset: 17, width: 4, values: PacketStatus;
+]);
+
+register!(grxfsiz, USB_BASE + 0x024);
+
+register!(gnptxfsiz, USB_BASE + 0x028, [
+    field: START, offset: 0, width: 16;
+    field: DEPTH, offset: 16, width: 16;
 ]);
 
-register!(grxstsp, USB_BASE + 0x020);
+register!(ghwcfg3, USB_BASE + 0x04c, access: ro, [
+    field: DFIFO_DEPTH, offset: 16, width: 16;
+]);
+
+// Index 0 is HPTXFSIZ, host mode only
+register!(dieptxf[16], USB_BASE + 0x100, stride: 0x4, [
+    field: START, offset: 0, width: 16;
+    field: DEPTH, offset: 16, width: 16;
+]);
+
+register!(dcfg, USB_BASE + 0x800, [
+    field: DEVADDR, offset: 4, width: 7;
+]);
 
 register!(dctl, USB_BASE + 0x804, [
+    bit: SFTDISCON, offset: 1;
     bit: SOFT_RESET1, offset: 8;
     bit: SOFT_RESET2, offset: 10;
 ]);
 
-register!(dsts, USB_BASE + 0x808);
+register!(dsts, USB_BASE + 0x808, access: ro);
 
-register!(doepctl1, USB_BASE + 0xb20, [
-    field: MPS, offset: 0, width: 11;
-    bit: USB_ACTIVE_EP, offset: 15;
-    field: EP_TYPE, offset: 18, width: 2;
+register!(doepmsk, USB_BASE + 0x814, [
+    bit: XFERCOMPL, offset: 0;
+    bit: SETUP_COMPLETED, offset: 3;
+]);
+
+register!(daintmsk, USB_BASE + 0x81c, [
+    field: OUTEPMSK, offset: 16, width: 16;
+]);
+
+register!(diepctl0, USB_BASE + 0x900, [
+    field: MPS, offset: 0, width: 2;
+    bit: STALL, offset: 21;
     bit: CNAK, offset: 26;
     bit: EPENA, offset: 31;
 ]);
 
-register!(diepint1, USB_BASE + 0x928, [
+register!(diepint0, USB_BASE + 0x908, access: w1c, [
     bit: XFERCOMPL, offset: 0;
 ]);
 
-register!(dieptsiz1, USB_BASE + 0x930, [
+register!(dieptsiz0, USB_BASE + 0x910, [
     field: XFERSIZE, offset: 0, width: 7;
     field: PKTCNT, offset: 19, width: 2;
 ]);
 
-register!(diepctl1, USB_BASE + 0x920, [
+register!(doepctl0, USB_BASE + 0xb00, [
+    bit: CNAK, offset: 26;
+    bit: EPENA, offset: 31;
+]);
+
+register!(doepint0, USB_BASE + 0xb08, access: w1c, [
+    bit: XFERCOMPL, offset: 0;
+    bit: SETUP_COMPLETED, offset: 3;
+]);
+
+register!(doeptsiz0, USB_BASE + 0xb10, [
+    field: XFERSIZE, offset: 0, width: 7;
+    bit: PKTCNT, offset: 19;
+    field: SUPCNT, offset: 29, width: 2;
+]);
+
+// Registers of endpoints 1-15, indexed by endpoint number. Index 0 aliases
+// the EP0 registers above, which use a narrower layout
+register!(diepctl[16], USB_BASE + 0x900, stride: 0x20, [
     field: MPS, offset: 0, width: 11;
     bit: USB_ACTIVE_EP, offset: 15;
-    field: EP_TYPE, offset: 18, width: 2;
+    field: EP_TYPE, offset: 18, width: 2, values: EndpointType;
+    bit: STALL, offset: 21;
     field: TXFNUM, offset: 22, width: 4;
     bit: CNAK, offset: 26;
+    bit: SD0PID, offset: 28;
     bit: EPENA, offset: 31;
 ]);
 
-register!(doepint1, USB_BASE + 0xb28, [
+register!(diepint[16], USB_BASE + 0x908, stride: 0x20, access: w1c, [
+    bit: XFERCOMPL, offset: 0;
+]);
+
+register!(dieptsiz[16], USB_BASE + 0x910, stride: 0x20, [
+    field: XFERSIZE, offset: 0, width: 19;
+    field: PKTCNT, offset: 19, width: 10;
+]);
+
+register!(doepctl[16], USB_BASE + 0xb00, stride: 0x20, [
+    field: MPS, offset: 0, width: 11;
+    bit: USB_ACTIVE_EP, offset: 15;
+    field: EP_TYPE, offset: 18, width: 2, values: EndpointType;
+    bit: STALL, offset: 21;
+    bit: CNAK, offset: 26;
+    bit: SD0PID, offset: 28;
+    bit: EPENA, offset: 31;
+]);
+
+register!(doepint[16], USB_BASE + 0xb08, stride: 0x20, access: w1c, [
     bit: XFERCOMPL, offset: 0;
     bit: SETUP_COMPLETED, offset: 3;
+    bit: OUTPKTERR, offset: 8;
+    bit: BBLEERR, offset: 12;
 ]);
 
-register!(doeptsiz1, USB_BASE + 0xb30, [
-    field: SPEED, offset: 0, width: 2;
-    bit: PKTCNT, offset: 19;
+register!(doeptsiz[16], USB_BASE + 0xb10, stride: 0x20, [
+    field: XFERSIZE, offset: 0, width: 19;
+    field: PKTCNT, offset: 19, width: 10;
 ]);
 
-register!(rx_fifo, USB_BASE + 0x1000);
-register!(tx_fifo, USB_BASE + 0x2000);
+register!(rx_fifo, USB_BASE + 0x1000, access: ro);
+// Push addresses, one per IN endpoint
+register!(tx_fifo[16], USB_BASE + 0x1000, stride: 0x1000, access: wo);
+
+pub type SetupPacket = [u8; 8];
+
+pub enum UsbEvent {
+    Reset,
+    Suspend,
+    Resume,
+    Disconnect,
+    /// Class or vendor request, standard ones are answered by the driver
+    Setup(SetupPacket),
+    BulkOut(usize),
+}
+
+/// What we enumerate as. Every gadget is a single interface with a bulk
+/// IN/OUT pair on EP1, so only the IDs, class and strings differ.
+pub struct Identity {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Interface class, subclass and protocol
+    pub class: [u8; 3],
+    /// Manufacturer, product and serial number
+    pub strings: [&'static str; 3],
+}
+
+impl Identity {
+    #[rustfmt::skip]
+    fn device_descriptor(&self) -> [u8; 18] {
+        let [vid_lo, vid_hi] = self.vendor_id.to_le_bytes();
+        let [pid_lo, pid_hi] = self.product_id.to_le_bytes();
+
+        [
+            18, DESC_DEVICE, 0x00, 0x02, 0, 0, 0, 64,
+            vid_lo, vid_hi, pid_lo, pid_hi,
+            0x00, 0x01, 1, 2, 3, 1,
+        ]
+    }
+
+    #[rustfmt::skip]
+    fn config_descriptor(&self, in_mps: usize, out_mps: usize) -> [u8; 32] {
+        let [class, subclass, protocol] = self.class;
+        let [in_lo, in_hi] = (in_mps as u16).to_le_bytes();
+        let [out_lo, out_hi] = (out_mps as u16).to_le_bytes();
+
+        [
+            // Configuration
+            9, DESC_CONFIG, 32, 0, 1, 1, 0, 0x80, 50,
+            // Interface
+            9, 4, 0, 0, 2, class, subclass, protocol, 0,
+            // EP1 IN, EP1 OUT
+            7, 5, 0x81, 2, in_lo, in_hi, 0,
+            7, 5, 0x01, 2, out_lo, out_hi, 0,
+        ]
+    }
+}
+
+const QUALIFIER_DESCRIPTOR: [u8; 10] = [10, DESC_QUALIFIER, 0x00, 0x02, 0, 0, 0, 64, 1, 0];
+const LANGUAGE_DESCRIPTOR: [u8; 4] = [4, DESC_STRING, 0x09, 0x04];
+
+fn put(buf: &mut [u8], data: &[u8]) -> usize {
+    buf[..data.len()].copy_from_slice(data);
+    data.len()
+}
+
+/// Strings that don't fit `buf` are cut short.
+fn put_string(buf: &mut [u8], s: &str) -> usize {
+    let chars = s.len().min((buf.len() - 2) / 2);
+    let len = 2 + 2 * chars;
+
+    buf[0] = len as u8;
+    buf[1] = DESC_STRING;
+    for (i, c) in s.bytes().take(chars).enumerate() {
+        buf[2 + 2 * i] = c;
+        buf[3 + 2 * i] = 0;
+    }
+
+    len
+}
 
 pub struct Usb {
     rx_buf: [u8; 512],
     rx_ptr: usize,
     rx_cnt: usize,
-    ep_mps: usize,
+    in_mps: usize,
+    out_mps: usize,
+    setup: SetupPacket,
+    /// `None` keeps the ROM's enumeration, we can't enumerate again then
+    identity: Option<&'static Identity>,
+    configured: bool,
+    suspended: bool,
+    /// Bulk OUT is stalled, so it mustn't be re-armed
+    out_halted: bool,
+    /// Bulk endpoints stay stalled through CLEAR_FEATURE, see `wedge_bulk`
+    wedged: bool,
+    /// From a bus reset or reconnect until the host picks a configuration
+    enumeration: Option<Phase>,
 }
 
 impl DriverMut for Usb {
@@ -89,162 +414,742 @@ impl DriverMut for Usb {
                 r.set_bit(dctl::SOFT_RESET1).set_bit(dctl::SOFT_RESET2);
             });
 
-            let speed = (dsts::read() >> 1) & 0x3;
+            if let Err(e) = self.configure_fifos() {
+                warn!("USB: FIFO setup failed: {}", e);
+            }
+            self.configure_endpoints();
+        }
+
+        SESSION.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Usb {
+    /// The ROM already enumerated us when we take over, so the bus starts out
+    /// configured.
+    pub fn new(identity: Option<&'static Identity>) -> Self {
+        Self {
+            rx_buf: [0; 512],
+            rx_ptr: 0,
+            rx_cnt: 0,
+            in_mps: 0,
+            out_mps: 0,
+            setup: [0; 8],
+            identity,
+            configured: true,
+            suspended: false,
+            out_halted: false,
+            wedged: false,
+            enumeration: None,
+        }
+    }
+
+    /// Of the bulk IN endpoint, what `bulk_write` sends per packet.
+    pub fn max_packet_size(&self) -> usize {
+        self.in_mps
+    }
+
+    /// Of the bulk OUT endpoint, anything shorter ends a transfer.
+    pub fn max_out_packet_size(&self) -> usize {
+        self.out_mps
+    }
+
+    pub fn rx_data(&self) -> &[u8] {
+        &self.rx_buf[..self.rx_cnt]
+    }
 
-            self.ep_mps = if speed == 0 {
-                uwriteln!(
-                    &mut Serial,
-                    "USB: Using USB High Speed Mode (MPS=512 bytes)"
+    /// Throws away whatever is left of the last received packet.
+    pub fn flush_rx(&mut self) {
+        self.rx_ptr = 0;
+        self.rx_cnt = 0;
+    }
+
+    /// Whether the host is around to talk to us.
+    pub fn is_active(&self) -> bool {
+        self.configured && !self.suspended
+    }
+
+    /// Partitions the FIFO RAM ourselves instead of relying on what the ROM
+    /// left behind: RX FIFO, EP0 TX FIFO, then one TX FIFO per IN endpoint.
+    unsafe fn configure_fifos(&mut self) -> Result<(), USBError> {
+        let mut used = RX_FIFO_WORDS + EP0_TX_FIFO_WORDS;
+        for ep in ENDPOINTS.iter().filter(|ep| ep.is_in()) {
+            used += ep.tx_fifo_words;
+        }
+
+        unsafe {
+            let total = ghwcfg3::read().get_field(ghwcfg3::DFIFO_DEPTH);
+            if used > total {
+                warn!(
+                    "USB: FIFO layout needs {} words, core has {}, keeping ROM setup",
+                    used, total
                 );
-                512
+                return Ok(());
+            }
+
+            grxfsiz::write(RX_FIFO_WORDS);
+            gnptxfsiz::new_scope(|r| {
+                use gnptxfsiz::*;
+
+                r.set_field(START, RX_FIFO_WORDS)
+                    .set_field(DEPTH, EP0_TX_FIFO_WORDS);
+            });
+
+            let mut start = RX_FIFO_WORDS + EP0_TX_FIFO_WORDS;
+            for ep in ENDPOINTS.iter().filter(|ep| ep.is_in()) {
+                dieptxf(ep.number).new_scope(|r| {
+                    use dieptxf::*;
+
+                    r.set_field(START, start).set_field(DEPTH, ep.tx_fifo_words);
+                });
+                start += ep.tx_fifo_words;
+            }
+
+            grstctl::new_scope(|r| {
+                r.set_field(grstctl::TXFNUM, TXFNUM_ALL)
+                    .set_bit(grstctl::TXFFLSH);
+            });
+            Deadline::after_us(FLUSH_TIMEOUT_US)
+                .wait(|| !grstctl::read().is_set_bit(grstctl::TXFFLSH))
+                .map_err(|_| USBError::Timeout)?;
+
+            grstctl::new_scope(|r| {
+                r.set_bit(grstctl::RXFFLSH);
+            });
+            Deadline::after_us(FLUSH_TIMEOUT_US)
+                .wait(|| !grstctl::read().is_set_bit(grstctl::RXFFLSH))
+                .map_err(|_| USBError::Timeout)?;
+        }
+
+        Ok(())
+    }
+
+    unsafe fn configure_endpoints(&mut self) {
+        unsafe {
+            let speed = (dsts::read() >> 1) & 0x3;
+            let high_speed = speed == 0;
+
+            self.in_mps = Self::packet_size(&BULK_IN, high_speed);
+            self.out_mps = Self::packet_size(&BULK_OUT, high_speed);
+
+            if high_speed {
+                info!("USB: Using USB High Speed Mode (MPS={} bytes)", self.in_mps);
             } else {
-                uwriteln!(&mut Serial, "USB: Using USB Full Speed Mode (MPS=64 bytes)");
-                64
-            };
+                info!("USB: Using USB Full Speed Mode (MPS={} bytes)", self.in_mps);
+            }
+
+            for ep in ENDPOINTS.iter() {
+                let mps = Self::packet_size(ep, high_speed);
+
+                if ep.is_in() {
+                    diepctl(ep.number).new_scope(|r| {
+                        use diepctl::*;
+
+                        r.set_bit(CNAK)
+                            .set_field(TXFNUM, ep.number)
+                            .set_enum(EP_TYPE, ep.kind)
+                            .set_bit(USB_ACTIVE_EP)
+                            .set_field(MPS, mps);
+                    });
+                } else {
+                    Self::arm_out(ep, mps);
+                }
+            }
+        }
+    }
 
-            doeptsiz1::read_modify_write(|r| {
-                use doeptsiz1::*;
+    fn packet_size(ep: &Endpoint, high_speed: bool) -> usize {
+        if high_speed { ep.mps } else { ep.mps.min(64) }
+    }
 
-                r.set_bit(PKTCNT).set_field(SPEED, speed);
+    /// Lets the core wake us up instead of being spun on. The interrupt
+    /// handler only masks the core again, events are still handled by `poll`.
+    #[cfg(feature = "interrupts")]
+    pub unsafe fn enable_interrupts(&mut self) {
+        unsafe {
+            doepmsk::new_scope(|r| {
+                r.set_bit(doepmsk::XFERCOMPL)
+                    .set_bit(doepmsk::SETUP_COMPLETED);
             });
 
-            doepctl1::new_scope(|r| {
-                use doepctl1::*;
+            let mut out_eps = bit(0);
+            for ep in ENDPOINTS.iter().filter(|ep| !ep.is_in()) {
+                out_eps |= bit(ep.number);
+            }
 
-                r.set_bit(EPENA)
-                    .set_bit(CNAK)
-                    .set_field(EP_TYPE, 2)
-                    .set_bit(USB_ACTIVE_EP)
-                    .set_field(MPS, self.ep_mps);
+            daintmsk::new_scope(|r| {
+                r.set_field(daintmsk::OUTEPMSK, out_eps);
             });
 
-            diepctl1::new_scope(|r| {
-                use diepctl1::*;
+            gintmsk::new_scope(|r| {
+                use gintmsk::*;
 
-                r.set_bit(CNAK)
-                    .set_field(TXFNUM, 1)
-                    .set_field(EP_TYPE, 2)
-                    .set_bit(USB_ACTIVE_EP)
-                    .set_field(MPS, self.ep_mps);
+                r.set_bit(OTGINT)
+                    .set_bit(RXFLVL)
+                    .set_bit(USBSUSP)
+                    .set_bit(USBRST)
+                    .set_bit(ENUMDONE)
+                    .set_bit(OEPINT)
+                    .set_bit(WKUPINT);
             });
+
+            Nvic::register(Irq::Usb, irq_handler);
+            Nvic::enable(Irq::Usb);
         }
     }
-}
 
-impl Usb {
-    pub fn new() -> Self {
-        Self {
-            rx_buf: [0; 512],
-            rx_ptr: 0,
-            rx_cnt: 0,
-            ep_mps: 0,
+    /// Sleeps until the core has an event for `poll`, or at the latest until
+    /// the next SysTick wrap so the caller gets to check its deadline and
+    /// kick the watchdog. Without interrupts this returns right away and the
+    /// caller keeps spinning.
+    pub unsafe fn wait(&self) {
+        #[cfg(feature = "interrupts")]
+        critical_section(|| unsafe {
+            if gintsts::read_raw() & gintmsk::read_raw() == 0 {
+                gahbcfg::read_modify_write(|r| {
+                    r.set_bit(gahbcfg::GLBLINTRMSK);
+                });
+                wfi();
+            }
+        });
+    }
+
+    /// Drops off the bus and comes back, so the host enumerates us again
+    /// instead of talking to whatever device the ROM presented.
+    pub unsafe fn reconnect(&mut self) {
+        unsafe {
+            dctl::read_modify_write(|r| {
+                r.set_bit(dctl::SFTDISCON);
+            });
+            mdelay(RECONNECT_DELAY_MS);
+            dctl::read_modify_write(|r| {
+                r.clear_bit(dctl::SFTDISCON);
+            });
         }
+
+        self.enumeration
+            .get_or_insert_with(|| Phase::begin("USB enum"));
     }
 
-    unsafe fn read_u8(&mut self) -> Result<u8, USBError> {
-        let mut hang_ctr = 0;
-        loop {
-            if self.rx_ptr < self.rx_cnt {
-                let b = self.rx_buf[self.rx_ptr];
-                self.rx_ptr += 1;
-                break Ok(b);
+    unsafe fn set_address(&mut self, addr: u8) {
+        unsafe {
+            dcfg::read_modify_write(|r| {
+                r.set_field(dcfg::DEVADDR, addr as usize);
+            });
+        }
+    }
+
+    unsafe fn ep0_receive_setup(&mut self) {
+        unsafe {
+            doeptsiz0::new_scope(|r| {
+                use doeptsiz0::*;
+
+                r.set_field(SUPCNT, 3)
+                    .set_bit(PKTCNT)
+                    .set_field(XFERSIZE, 3 * 8);
+            });
+
+            doepctl0::read_modify_write(|r| {
+                r.set_bit(doepctl0::EPENA).set_bit(doepctl0::CNAK);
+            });
+        }
+    }
+
+    pub unsafe fn ep0_stall(&mut self) {
+        unsafe {
+            diepctl0::read_modify_write(|r| {
+                r.set_bit(diepctl0::STALL);
+            });
+            self.ep0_receive_setup();
+        }
+    }
+
+    /// Sends a single control IN packet, an empty `data` is the status stage.
+    pub unsafe fn ep0_write(&mut self, data: &[u8]) -> Result<(), USBError> {
+        unsafe {
+            dieptsiz0::new_scope(|r| {
+                use dieptsiz0::*;
+
+                r.set_field(PKTCNT, 1).set_field(XFERSIZE, data.len());
+            });
+
+            diepctl0::read_modify_write(|r| {
+                use diepctl0::*;
+
+                r.set_field(MPS, 0).set_bit(EPENA).set_bit(CNAK);
+            });
+
+            Self::fifo_write(0, data);
+
+            let deadline = Deadline::after_us(WRITE_TIMEOUT_US);
+            loop {
+                if diepint0::read().is_set_bit(diepint0::XFERCOMPL) {
+                    diepint0::write_raw(1);
+                    break Ok(());
+                }
+
+                if deadline.expired() {
+                    break Err(USBError::Timeout);
+                }
             }
+        }
+    }
+
+    /// Sends one bulk IN packet of at most `max_packet_size` bytes.
+    pub unsafe fn bulk_write(&mut self, data: &[u8]) -> Result<(), USBError> {
+        if !self.configured {
+            return Err(USBError::Disconnected);
+        }
+
+        unsafe { Self::bulk_in(self.in_mps, data) }
+    }
 
-            let status = unsafe { gintsts::read() };
+    unsafe fn bulk_in(mps: usize, data: &[u8]) -> Result<(), USBError> {
+        unsafe {
+            let ep = BULK_IN.number;
 
-            if status.is_set_bit(gintsts::RXFLVL) {
-                let rx_status = unsafe { grxstsp::read() };
-                let packet_status = (rx_status >> 17) & 0xf;
-                let byte_count = ((rx_status >> 4) & 0x7ff) as usize;
+            if diepctl(ep).read().is_set_bit(diepctl::STALL) {
+                return Err(USBError::Stalled);
+            }
 
-                // 2 = OUT received, 6 = Setup received
-                if (packet_status == 2 || packet_status == 6) && byte_count > 0 {
-                    self.rx_ptr = 0;
-                    self.rx_cnt = 0;
+            dieptsiz(ep).new_scope(|r| {
+                use dieptsiz::*;
+
+                r.set_field(PKTCNT, 1).set_field(XFERSIZE, data.len());
+            });
+
+            diepctl(ep).new_scope(|r| {
+                use diepctl::*;
+
+                r.set_bit(EPENA)
+                    .set_bit(CNAK)
+                    .set_field(TXFNUM, ep)
+                    .set_enum(EP_TYPE, BULK_IN.kind)
+                    .set_bit(USB_ACTIVE_EP)
+                    .set_field(MPS, mps);
+            });
+
+            Self::fifo_write(ep, data);
+
+            let deadline = Deadline::after_us(WRITE_TIMEOUT_US);
+            loop {
+                let intr = diepint(ep).read();
+                if intr.is_set_bit(diepint::XFERCOMPL) {
+                    diepint(ep).write_raw(1);
+                    break Ok(());
+                }
+
+                // Left pending for `poll` to pick up
+                let status = gintsts::read();
+                if status.is_set_bit(gintsts::USBRST) {
+                    break Err(USBError::Disconnected);
+                }
 
-                    let words = (byte_count + 3) / 4;
-                    for _ in 0..words {
-                        let val = unsafe { rx_fifo::read() };
-                        let bytes = val.to_le_bytes();
-                        for k in 0..4 {
-                            if self.rx_cnt < 512 && self.rx_cnt < byte_count {
-                                self.rx_buf[self.rx_cnt] = bytes[k];
-                                self.rx_cnt += 1;
-                            }
-                        }
+                if status.is_set_bit(gintsts::OTGINT) {
+                    let otg = gotgint::read();
+                    if otg.is_set_bit(gotgint::SESENDDET) {
+                        break Err(USBError::Disconnected);
                     }
+
+                    // Nothing that ends the transfer, but it would keep
+                    // OTGINT set
+                    gotgint::write_raw(otg.raw());
+                }
+
+                if deadline.expired() {
+                    break Err(USBError::Timeout);
                 }
             }
+        }
+    }
 
-            if status.is_set_bit(gintsts::OEPINT) {
-                unsafe {
-                    doepint1::read_modify_write(|r| {
-                        use doepint1::*;
-
-                        doepint1::write_raw(r.raw());
-
-                        if r.is_set_bit(XFERCOMPL) || r.is_set_bit(SETUP_COMPLETED) {
-                            doeptsiz1::read_modify_write(|r| {
-                                r.set_bit(doeptsiz1::PKTCNT)
-                                    .set_field(doeptsiz1::SPEED, self.ep_mps);
-                            });
-
-                            doepctl1::new_scope(|r| {
-                                use doepctl1::*;
-
-                                r.set_bit(EPENA)
-                                    .set_bit(CNAK)
-                                    .set_field(EP_TYPE, TYPE_BULK)
-                                    .set_bit(USB_ACTIVE_EP)
-                                    .set_field(MPS, self.ep_mps);
-                            });
-                        }
-                    });
+    unsafe fn fifo_write(ep: usize, data: &[u8]) {
+        for chunk in data.chunks(4) {
+            let mut word = [0u8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            unsafe { tx_fifo(ep).write_raw(u32::from_le_bytes(word) as usize) };
+        }
+    }
+
+    unsafe fn fifo_read(&mut self, byte_count: usize) {
+        self.rx_ptr = 0;
+        self.rx_cnt = 0;
+
+        let words = (byte_count + 3) / 4;
+        for _ in 0..words {
+            let val = unsafe { rx_fifo::read() };
+            let bytes = val.to_le_bytes();
+            for k in 0..4 {
+                if self.rx_cnt < 512 && self.rx_cnt < byte_count {
+                    self.rx_buf[self.rx_cnt] = bytes[k];
+                    self.rx_cnt += 1;
                 }
             }
+        }
+    }
 
-            hang_ctr += 1;
-            if hang_ctr > 1_000_000 {
-                break Err(USBError::Timeout);
+    unsafe fn rearm_bulk_out(&mut self) {
+        if !self.out_halted {
+            unsafe { Self::arm_out(&BULK_OUT, self.out_mps) };
+        }
+    }
+
+    /// Stalls bulk OUT until the host clears the halt, to refuse data it
+    /// wants to send.
+    pub unsafe fn halt_bulk_out(&mut self) {
+        self.out_halted = true;
+
+        unsafe {
+            doepctl(BULK_OUT.number).read_modify_write(|r| {
+                r.set_bit(doepctl::STALL);
+            });
+        }
+    }
+
+    /// Stalls both bulk endpoints and keeps them stalled through CLEAR_FEATURE
+    /// until `unwedge_bulk`, for gadgets that need the host to reset them.
+    pub unsafe fn wedge_bulk(&mut self) {
+        self.wedged = true;
+
+        unsafe {
+            diepctl(BULK_IN.number).read_modify_write(|r| {
+                r.set_bit(diepctl::STALL);
+            });
+            self.halt_bulk_out();
+        }
+    }
+
+    /// Lets the host's CLEAR_FEATURE through again, the endpoints stay
+    /// stalled until then.
+    pub fn unwedge_bulk(&mut self) {
+        self.wedged = false;
+    }
+
+    /// CLEAR_FEATURE(ENDPOINT_HALT), which also resets the data toggle.
+    unsafe fn clear_halt(&mut self, address: u8) {
+        if self.wedged {
+            return;
+        }
+
+        let number = (address & !ENDPOINT_DIR_IN) as usize;
+
+        unsafe {
+            if address & ENDPOINT_DIR_IN != 0 && number == BULK_IN.number {
+                diepctl(number).read_modify_write(|r| {
+                    r.clear_bit(diepctl::STALL).set_bit(diepctl::SD0PID);
+                });
+            } else if address & ENDPOINT_DIR_IN == 0 && number == BULK_OUT.number {
+                doepctl(number).read_modify_write(|r| {
+                    r.clear_bit(doepctl::STALL).set_bit(doepctl::SD0PID);
+                });
+                self.out_halted = false;
+                self.rearm_bulk_out();
             }
         }
     }
 
-    unsafe fn write_u8(&mut self, b: u8) -> Result<(), USBError> {
+    /// Makes room for one more packet on an OUT endpoint.
+    unsafe fn arm_out(ep: &Endpoint, mps: usize) {
         unsafe {
-            dieptsiz1::new_scope(|r| {
-                use dieptsiz1::*;
+            doeptsiz(ep.number).new_scope(|r| {
+                use doeptsiz::*;
 
-                r.set_field(PKTCNT, 1).set_field(XFERSIZE, 1);
+                r.set_field(PKTCNT, 1).set_field(XFERSIZE, mps);
             });
 
-            diepctl1::new_scope(|r| {
-                use diepctl1::*;
+            doepctl(ep.number).new_scope(|r| {
+                use doepctl::*;
 
                 r.set_bit(EPENA)
                     .set_bit(CNAK)
-                    .set_field(EP_TYPE, TYPE_BULK)
+                    .set_enum(EP_TYPE, ep.kind)
                     .set_bit(USB_ACTIVE_EP)
-                    .set_field(MPS, self.ep_mps);
+                    .set_field(MPS, mps);
             });
+        }
+    }
 
-            tx_fifo::write(b as usize);
+    /// Answers chapter 9 requests, returns `false` for anything the gadget
+    /// has to handle itself.
+    unsafe fn handle_standard_setup(&mut self) -> Result<bool, USBError> {
+        let [
+            request_type,
+            request,
+            value_lo,
+            value_hi,
+            index_lo,
+            _,
+            length_lo,
+            length_hi,
+        ] = self.setup;
+        let length = u16::from_le_bytes([length_lo, length_hi]) as usize;
 
-            let mut timeout = 10_000_000;
-            loop {
-                let intr = diepint1::read();
-                if intr.is_set_bit(diepint1::XFERCOMPL) {
-                    diepint1::write_raw(1);
-                    break Ok(());
+        unsafe {
+            match (request_type, request) {
+                (0x80, REQ_GET_DESCRIPTOR) => self.send_descriptor(value_hi, value_lo, length)?,
+                (0x00, REQ_SET_ADDRESS) => {
+                    self.set_address(value_lo);
+                    self.ep0_write(&[])?;
                 }
+                (0x00, REQ_SET_CONFIGURATION) => {
+                    self.configured = value_lo != 0;
+                    self.ep0_write(&[])?;
 
-                timeout -= 1;
-                if timeout == 0 {
-                    break Err(USBError::Timeout);
+                    if self.configured
+                        && let Some(phase) = self.enumeration.take()
+                    {
+                        phase.end();
+                    }
                 }
+                (0x80, REQ_GET_CONFIGURATION) => self.ep0_write(&[self.configured as u8])?,
+                (0x80..=0x82, REQ_GET_STATUS) => self.ep0_write(&[0, 0])?,
+                (0x02, REQ_CLEAR_FEATURE) if value_lo == FEATURE_ENDPOINT_HALT => {
+                    self.clear_halt(index_lo);
+                    self.ep0_write(&[])?;
+                }
+                (0x00..=0x02, REQ_CLEAR_FEATURE) | (0x01, REQ_SET_INTERFACE) => {
+                    self.ep0_write(&[])?
+                }
+                _ => return Ok(false),
+            }
+        }
+
+        Ok(true)
+    }
+
+    unsafe fn send_descriptor(
+        &mut self,
+        kind: u8,
+        index: u8,
+        length: usize,
+    ) -> Result<(), USBError> {
+        let mut buf = [0u8; 64];
+        let index = index as usize;
+        let Some(identity) = self.identity else {
+            warn!("USB: Host enumerates us again, the ROM's descriptors are unknown");
+            unsafe { self.ep0_stall() };
+            return Ok(());
+        };
+        let strings = &identity.strings;
+
+        let len = match kind {
+            DESC_DEVICE => put(&mut buf, &identity.device_descriptor()),
+            DESC_CONFIG => put(
+                &mut buf,
+                &identity.config_descriptor(self.in_mps, self.out_mps),
+            ),
+            DESC_QUALIFIER => put(&mut buf, &QUALIFIER_DESCRIPTOR),
+            DESC_STRING if index == 0 => put(&mut buf, &LANGUAGE_DESCRIPTOR),
+            DESC_STRING if index <= strings.len() => put_string(&mut buf, strings[index - 1]),
+            _ => {
+                unsafe { self.ep0_stall() };
+                return Ok(());
+            }
+        };
+
+        unsafe { self.ep0_write(&buf[..len.min(length)]) }
+    }
+
+    /// Handles at most one pending core event without blocking. Bus state
+    /// changes re-run endpoint setup so the host can come back at any time.
+    pub unsafe fn poll(&mut self) -> Result<Option<UsbEvent>, USBError> {
+        let status = unsafe { gintsts::read() };
+
+        if status.is_set_bit(gintsts::USBRST) {
+            self.flush_rx();
+            self.configured = false;
+            self.suspended = false;
+            self.out_halted = false;
+            self.wedged = false;
+            self.enumeration
+                .get_or_insert_with(|| Phase::begin("USB enum"));
+
+            unsafe {
+                self.set_address(0);
+                self.ep0_receive_setup();
+                gintsts::new_scope(|r| {
+                    r.set_bit(gintsts::USBRST);
+                });
+            }
+            return Ok(Some(UsbEvent::Reset));
+        }
+
+        if status.is_set_bit(gintsts::ENUMDONE) {
+            unsafe {
+                self.configure_endpoints();
+                self.ep0_receive_setup();
+                gintsts::new_scope(|r| {
+                    r.set_bit(gintsts::ENUMDONE);
+                });
+            }
+            return Ok(None);
+        }
+
+        if status.is_set_bit(gintsts::USBSUSP) {
+            self.suspended = true;
+            unsafe {
+                gintsts::new_scope(|r| {
+                    r.set_bit(gintsts::USBSUSP);
+                });
+            }
+            return Ok(Some(UsbEvent::Suspend));
+        }
+
+        if status.is_set_bit(gintsts::WKUPINT) {
+            self.suspended = false;
+            unsafe {
+                gintsts::new_scope(|r| {
+                    r.set_bit(gintsts::WKUPINT);
+                });
+            }
+            return Ok(Some(UsbEvent::Resume));
+        }
+
+        if status.is_set_bit(gintsts::OTGINT) {
+            let otg = unsafe { gotgint::read() };
+            unsafe { gotgint::write_raw(otg.raw()) };
+
+            if otg.is_set_bit(gotgint::SESENDDET) {
+                self.configured = false;
+                self.flush_rx();
+                return Ok(Some(UsbEvent::Disconnect));
+            }
+        }
+
+        if status.is_set_bit(gintsts::RXFLVL) {
+            let rx_status = unsafe { grxstsp::read() };
+            let ep = rx_status.get_field(grxstsp::EPNUM);
+            let byte_count = rx_status.get_field(grxstsp::BCNT);
+
+            match rx_status.get_enum(grxstsp::PKTSTS) {
+                Some(PacketStatus::SetupReceived) => unsafe {
+                    self.fifo_read(byte_count);
+                    self.setup.copy_from_slice(&self.rx_buf[..8]);
+                    self.rx_cnt = 0;
+                },
+                Some(PacketStatus::OutReceived) if byte_count > 0 => unsafe {
+                    self.fifo_read(byte_count);
+                    if byte_count > self.rx_buf.len() {
+                        self.rx_cnt = 0;
+                        return Err(USBError::FifoOverflow);
+                    }
+                    if ep == BULK_OUT.number {
+                        return Ok(Some(UsbEvent::BulkOut(byte_count)));
+                    }
+                    self.rx_cnt = 0;
+                },
+                _ => {}
+            }
+        }
+
+        if status.is_set_bit(gintsts::OEPINT) {
+            unsafe {
+                let ep0 = doepint0::read();
+                doepint0::write_raw(ep0.raw());
+
+                if ep0.is_set_bit(doepint0::SETUP_COMPLETED) {
+                    self.ep0_receive_setup();
+                    if !self.handle_standard_setup()? {
+                        return Ok(Some(UsbEvent::Setup(self.setup)));
+                    }
+                } else if ep0.is_set_bit(doepint0::XFERCOMPL) {
+                    self.ep0_receive_setup();
+                }
+
+                let out = doepint(BULK_OUT.number).read();
+                doepint(BULK_OUT.number).write_raw(out.raw());
+
+                if out.is_set_bit(doepint::XFERCOMPL) {
+                    self.rearm_bulk_out();
+                }
+
+                if out.is_set_bit(doepint::BBLEERR) {
+                    self.rearm_bulk_out();
+                    return Err(USBError::Babble);
+                }
+
+                if out.is_set_bit(doepint::OUTPKTERR) {
+                    self.rearm_bulk_out();
+                    return Err(USBError::FifoOverflow);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    unsafe fn read_u8(&mut self) -> Result<u8, USBError> {
+        let mut deadline = Deadline::after_us(READ_TIMEOUT_US);
+        loop {
+            Watchdog::kick();
+
+            if self.rx_ptr < self.rx_cnt {
+                let b = self.rx_buf[self.rx_ptr];
+                self.rx_ptr += 1;
+                break Ok(b);
+            }
+
+            match unsafe { self.poll()? } {
+                Some(UsbEvent::Setup(_)) => unsafe {
+                    self.ep0_stall();
+                    break Err(USBError::UnexpectedSetup);
+                },
+                Some(UsbEvent::Reset) => {
+                    info!("USB: Bus reset");
+                }
+                Some(UsbEvent::Suspend) => {
+                    debug!("USB: Suspended");
+                }
+                Some(UsbEvent::Resume) => {
+                    debug!("USB: Resumed");
+                }
+                Some(UsbEvent::Disconnect) => {
+                    info!("USB: Host disconnected");
+                }
+                Some(UsbEvent::BulkOut(_)) => {}
+                None => unsafe { self.wait() },
+            }
+
+            // Keep waiting for as long as it takes the host to come back
+            if !self.is_active() {
+                deadline = Deadline::after_us(READ_TIMEOUT_US);
+                continue;
+            }
+
+            if deadline.expired() {
+                break Err(USBError::Timeout);
             }
         }
     }
+
+    unsafe fn write_u8(&mut self, b: u8) -> Result<(), USBError> {
+        unsafe { self.bulk_write(&[b]) }
+    }
+}
+
+/// Lets the host know we are gone instead of leaving it to time out. Only
+/// done if there is a session with the bulk endpoints up, the `Usb` itself
+/// may be in any state.
+pub unsafe fn report_panic() {
+    if !SESSION.load(Ordering::Relaxed) {
+        return;
+    }
+
+    unsafe {
+        let ctl = diepctl(BULK_IN.number).read();
+        if ctl.is_set_bit(diepctl::USB_ACTIVE_EP) {
+            let _ = Usb::bulk_in(ctl.get_field(diepctl::MPS), &[PANIC_STATUS]);
+        }
+    }
+}
+
+#[cfg(feature = "interrupts")]
+fn irq_handler() {
+    unsafe {
+        gahbcfg::read_modify_write(|r| {
+            r.clear_bit(gahbcfg::GLBLINTRMSK);
+        });
+    }
 }
 
 impl SimpleRead for Usb {
@@ -270,3 +1175,31 @@ impl SimpleWrite for Usb {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::mmio::sim;
+
+    fn configure_fifos() -> Result<(), USBError> {
+        sim::set(USB_BASE + 0x04c, 0x1000 << 16);
+
+        unsafe { Usb::new(None).configure_fifos() }
+    }
+
+    #[test]
+    fn flushes_fifos() {
+        sim::reset();
+        // The core clears the flush bits once done
+        sim::on_write(USB_BASE + 0x010, |_| 0);
+
+        assert!(configure_fifos().is_ok());
+    }
+
+    #[test]
+    fn times_out_on_stuck_fifo_flush() {
+        sim::reset();
+
+        assert!(matches!(configure_fifos(), Err(USBError::Timeout)));
+    }
+}
@@ -0,0 +1,123 @@
+#[cfg(not(test))]
+use core::arch::global_asm;
+
+#[cfg(feature = "interrupts")]
+use crate::drivers::nvic;
+use crate::drivers::{uart::Serial, writel};
+use crate::log::error;
+
+// The M0 has no VTOR, vectors are always fetched from here. That this is
+// writable RAM on this SoC is still to be confirmed on hardware.
+const VECTOR_TABLE: usize = 0x0;
+/// Initial MSP and reset vector, both left to the ROM
+const ROM_VECTORS: usize = 2;
+pub(super) const EXCEPTION_COUNT: usize = 16;
+pub(super) const IRQ_COUNT: usize = 32;
+const HARD_FAULT: usize = 3;
+#[cfg(feature = "interrupts")]
+const SYS_TICK: usize = nvic::Exception::SysTick as usize;
+
+/// What the core pushes on exception entry, lowest address first.
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+}
+
+#[derive(Clone, Copy)]
+union Vector {
+    handler: unsafe extern "C" fn(),
+    reserved: usize,
+}
+
+unsafe extern "C" {
+    fn hard_fault_trampoline();
+}
+
+// We never switch to the process stack, so the frame is always on MSP
+#[cfg(not(test))]
+global_asm!(
+    ".syntax unified
+    .code 16
+
+    .global hard_fault_trampoline
+    .section .text.hard_fault_trampoline
+    .thumb_func
+    hard_fault_trampoline:
+        mrs r0, msp
+        ldr r1, =hard_fault
+        bx r1"
+);
+
+unsafe extern "C" fn default_handler() {
+    loop {}
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn hard_fault(frame: &ExceptionFrame) -> ! {
+    error!("HardFault");
+    error!(
+        "\tr0 {:#x} r1 {:#x} r2 {:#x} r3 {:#x}",
+        frame.r0, frame.r1, frame.r2, frame.r3
+    );
+    error!(
+        "\tr12 {:#x} lr {:#x} pc {:#x} xpsr {:#x}",
+        frame.r12, frame.lr, frame.pc, frame.xpsr
+    );
+
+    unsafe { crate::crash::Crash::record_fault(frame) };
+    let _ = Serial::flush();
+
+    loop {}
+}
+
+const fn build_vectors() -> [Vector; EXCEPTION_COUNT + IRQ_COUNT] {
+    let mut v = [Vector {
+        handler: default_handler,
+    }; EXCEPTION_COUNT + IRQ_COUNT];
+
+    // Not installed, a reset goes through the ROM as it always did
+    v[0] = Vector { reserved: 0 };
+    v[1] = Vector { reserved: 0 };
+    v[HARD_FAULT] = Vector {
+        handler: hard_fault_trampoline,
+    };
+
+    // Drivers hook into the lines through `Nvic::register`, and into
+    // exceptions through `Nvic::register_exception`
+    #[cfg(feature = "interrupts")]
+    {
+        v[SYS_TICK] = Vector {
+            handler: nvic::dispatch,
+        };
+
+        let mut i = EXCEPTION_COUNT;
+        while i < EXCEPTION_COUNT + IRQ_COUNT {
+            v[i] = Vector {
+                handler: nvic::dispatch,
+            };
+            i += 1;
+        }
+    }
+
+    v
+}
+
+/// Linked into `.vectors`, see `payload.ld`. The M0 can't be pointed at it,
+/// so `install` copies it to where the core looks.
+#[unsafe(link_section = ".vectors")]
+static VECTORS: [Vector; EXCEPTION_COUNT + IRQ_COUNT] = build_vectors();
+
+/// Replaces the ROM's vectors with ours, all but the initial MSP and reset
+/// vector. The first thing to do so faults don't end up in the ROM.
+pub unsafe fn install() {
+    for (i, vector) in VECTORS.iter().enumerate().skip(ROM_VECTORS) {
+        unsafe { writel(VECTOR_TABLE + i * 4, vector.reserved) };
+    }
+}
@@ -0,0 +1,88 @@
+use crate::drivers::{StatelessDriver, bit, readl, writel};
+
+// Nothing touches the watchdog or the reset status without the `watchdog`
+// feature, which stays off by default until both are confirmed on hardware
+pub(super) const ENABLED: bool = cfg!(feature = "watchdog");
+
+// Register layout as driven by Linux's zx2967_wdt, the base of the M0's
+// instance is still to be confirmed on hardware
+pub(super) const WDT_BASE: usize = 0x00137000;
+const WDT_CONFIG: usize = WDT_BASE + 0x04;
+const WDT_LOAD: usize = WDT_BASE + 0x08;
+const WDT_REFRESH: usize = WDT_BASE + 0x18;
+const WDT_START: usize = WDT_BASE + 0x1c;
+#[cfg(any(test, feature = "mmio-trace"))]
+pub(super) const WDT_END: usize = WDT_BASE + 0x20;
+
+/// Writes are ignored unless they carry this in the upper half
+const WRITE_KEY: usize = 0x1234 << 16;
+const FLAG_START: usize = bit(0);
+/// Toggling any of these restarts the count from `WDT_LOAD`
+const REFRESH_MASK: usize = 0x3f;
+const VALUE_MASK: usize = 0xffff;
+
+/// Brings the 32 kHz clock down to one tick per millisecond
+const MS_DIV: usize = 32;
+/// Enough for the slowest DRAM training, everything that can take longer
+/// kicks on its own
+const TIMEOUT_MS: usize = 10_000;
+
+/// The counter runs off the 32 kHz clock divided by `n`.
+const fn config_div(n: usize) -> usize {
+    ((n - 1) & 0xff) << 8
+}
+
+/// Counts down from the load value and resets the whole SoC at zero.
+pub struct Watchdog;
+
+impl StatelessDriver for Watchdog {
+    /// Arms the watchdog, from here on something has to `kick` it at least
+    /// every `TIMEOUT_MS`.
+    unsafe fn init() -> Self {
+        if ENABLED {
+            unsafe { Self::start(MS_DIV, TIMEOUT_MS) };
+        }
+
+        Self
+    }
+}
+
+impl Watchdog {
+    pub fn kick() {
+        if !ENABLED {
+            return;
+        }
+
+        unsafe {
+            let value = readl(WDT_REFRESH) ^ REFRESH_MASK;
+            writel(WDT_REFRESH, WRITE_KEY | (value & VALUE_MASK));
+        }
+    }
+
+    /// For when nobody is left to kick it, like after the AP took over.
+    pub unsafe fn disable() {
+        if ENABLED {
+            unsafe { writel(WDT_START, WRITE_KEY) };
+        }
+    }
+
+    /// Resets the SoC as fast as the watchdog allows. Without one it only
+    /// halts.
+    pub unsafe fn reset() -> ! {
+        if ENABLED {
+            unsafe { Self::start(1, 1) };
+        }
+
+        loop {}
+    }
+
+    unsafe fn start(div: usize, count: usize) {
+        unsafe {
+            Self::disable();
+            writel(WDT_CONFIG, WRITE_KEY | config_div(div));
+            writel(WDT_LOAD, WRITE_KEY | (count & VALUE_MASK));
+            Self::kick();
+            writel(WDT_START, WRITE_KEY | FLAG_START);
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use core::slice;
+use derive_ctor::ctor;
+use simpleport::SimpleWrite;
+
+use crate::{
+    drivers::{
+        dram::{DRAM_BASE, DramSize},
+        timer::Deadline,
+        uart::Serial,
+        watchdog::Watchdog,
+        zte_protocol::ZteProtocol,
+    },
+    err::{Error, UARTError, YmodemError},
+    log::info,
+    timing::Phase,
+};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// Sent instead of NAK to ask for CRC-16 rather than checksum packets
+const CRC_REQUEST: u8 = b'C';
+
+const SMALL_BLOCK: usize = 128;
+const LARGE_BLOCK: usize = 1024;
+
+/// How long to wait for the sender before asking again
+const START_TIMEOUT_MS: u64 = 3_000;
+/// Within a packet, and the quiet time that ends a purge
+const BYTE_TIMEOUT_MS: u64 = 1_000;
+/// How long the line may keep babbling after a damaged packet
+const PURGE_TIMEOUT_MS: u64 = 3_000;
+const MAX_ERRORS: usize = 10;
+
+enum Packet {
+    Data { block: u8, len: usize },
+    End,
+    Cancel,
+}
+
+/// XMODEM-1K and YMODEM receiver, the image is loaded at the start of DRAM
+/// and run once the transfer finished. Which of the two the sender speaks is
+/// told apart by the first block, YMODEM starts with a header in block 0.
+#[derive(ctor)]
+pub struct Ymodem {
+    serial: Serial,
+    dram_size: DramSize,
+}
+
+impl Ymodem {
+    pub unsafe fn dispatch(&mut self) -> Result<(), Error> {
+        info!("Waiting for XMODEM/YMODEM upload to {:#x}", DRAM_BASE);
+
+        let phase = Phase::begin("YMODEM");
+        let result = unsafe { self.receive() };
+        phase.end();
+
+        let len = match result {
+            Ok(len) => len,
+            Err(e) => {
+                self.send(CAN);
+                self.send(CAN);
+                return Err(e);
+            }
+        };
+
+        info!("Received {} bytes, booting from {:#x}", len, DRAM_BASE);
+        unsafe { ZteProtocol::boot_ap(DRAM_BASE) };
+
+        Ok(())
+    }
+
+    /// Returns the image length, for XMODEM this includes the padding of the
+    /// last block.
+    unsafe fn receive(&mut self) -> Result<usize, Error> {
+        let mut buf = [0; LARGE_BLOCK];
+        let mut started = false;
+        let mut ymodem = false;
+        let mut file_size = None;
+        let mut expected: u8 = 1;
+        let mut offset = 0;
+        let mut errors = 0;
+        let mut eot_seen = false;
+
+        self.send(CRC_REQUEST);
+
+        loop {
+            Watchdog::kick();
+
+            let timeout = if started {
+                BYTE_TIMEOUT_MS
+            } else {
+                START_TIMEOUT_MS
+            };
+
+            let packet = match Self::read_packet(&mut buf, timeout) {
+                Ok(packet) => packet,
+                // Nobody is sending yet, keep asking
+                Err(Error::UART(UARTError::Timeout)) if !started => {
+                    self.send(CRC_REQUEST);
+                    continue;
+                }
+                Err(e) if e.is_recoverable() => {
+                    errors += 1;
+                    if errors > MAX_ERRORS {
+                        return Err(YmodemError::TooManyErrors.into());
+                    }
+
+                    Self::purge()?;
+                    self.send(if started { NAK } else { CRC_REQUEST });
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            match packet {
+                Packet::Data { block: 0, len } if !started => {
+                    file_size = Self::header_size(&buf[..len])?;
+                    if file_size.is_some_and(|size| size > self.dram_size.usable_bytes()) {
+                        return Err(YmodemError::TooLarge.into());
+                    }
+                    ymodem = true;
+                    started = true;
+
+                    self.send(ACK);
+                    self.send(CRC_REQUEST);
+                }
+                Packet::Data { block, len } if block == expected => {
+                    let len = match file_size {
+                        Some(size) => len.min(size - offset),
+                        None => len,
+                    };
+                    if offset + len > self.dram_size.usable_bytes() {
+                        return Err(YmodemError::TooLarge.into());
+                    }
+
+                    unsafe {
+                        slice::from_raw_parts_mut((DRAM_BASE + offset) as *mut u8, len)
+                            .copy_from_slice(&buf[..len]);
+                    }
+
+                    offset += len;
+                    expected = expected.wrapping_add(1);
+                    errors = 0;
+                    started = true;
+
+                    self.send(ACK);
+                }
+                // Our ACK got lost, the sender repeats the last block
+                Packet::Data { block, .. } if started && block == expected.wrapping_sub(1) => {
+                    self.send(ACK);
+                }
+                Packet::Data { .. } => return Err(YmodemError::Sequence.into()),
+                // Nothing to end yet, a sender that gave up before the first
+                // block or noise, there is nothing to boot either way
+                Packet::End if !started => self.send(CRC_REQUEST),
+                Packet::End if offset == 0 || file_size.is_some_and(|size| offset < size) => {
+                    return Err(YmodemError::Truncated.into());
+                }
+                // YMODEM senders expect the first EOT to be NAKed
+                Packet::End if ymodem && !eot_seen => {
+                    eot_seen = true;
+                    self.send(NAK);
+                }
+                Packet::End => {
+                    self.send(ACK);
+
+                    if ymodem {
+                        unsafe { self.finish_batch(&mut buf) };
+                    }
+
+                    break Ok(file_size.unwrap_or(offset));
+                }
+                Packet::Cancel => return Err(YmodemError::Cancelled.into()),
+            }
+        }
+    }
+
+    /// YMODEM closes the batch with an empty header, it carries nothing we
+    /// need so failing to read it isn't fatal.
+    unsafe fn finish_batch(&mut self, buf: &mut [u8; LARGE_BLOCK]) {
+        for _ in 0..MAX_ERRORS {
+            self.send(CRC_REQUEST);
+
+            if let Ok(Packet::Data { block: 0, .. }) = Self::read_packet(buf, BYTE_TIMEOUT_MS) {
+                self.send(ACK);
+                return;
+            }
+
+            if Self::purge().is_err() {
+                return;
+            }
+        }
+    }
+
+    fn read_packet(buf: &mut [u8; LARGE_BLOCK], timeout_ms: u64) -> Result<Packet, Error> {
+        let len = match Serial::getc(Some(timeout_ms))? {
+            SOH => SMALL_BLOCK,
+            STX => LARGE_BLOCK,
+            EOT => return Ok(Packet::End),
+            // One could be noise, senders cancel with two in a row
+            CAN => {
+                return match Serial::getc(Some(BYTE_TIMEOUT_MS))? {
+                    CAN => Ok(Packet::Cancel),
+                    _ => Err(YmodemError::BadPacket.into()),
+                };
+            }
+            _ => return Err(YmodemError::BadPacket.into()),
+        };
+
+        let block = Serial::getc(Some(BYTE_TIMEOUT_MS))?;
+        let block_inv = Serial::getc(Some(BYTE_TIMEOUT_MS))?;
+
+        for b in &mut buf[..len] {
+            *b = Serial::getc(Some(BYTE_TIMEOUT_MS))?;
+        }
+
+        let crc_hi = Serial::getc(Some(BYTE_TIMEOUT_MS))?;
+        let crc_lo = Serial::getc(Some(BYTE_TIMEOUT_MS))?;
+
+        if block != !block_inv || crc16(&buf[..len]) != u16::from_be_bytes([crc_hi, crc_lo]) {
+            return Err(YmodemError::BadPacket.into());
+        }
+
+        Ok(Packet::Data { block, len })
+    }
+
+    /// Block 0 holds the NUL terminated file name followed by the size in
+    /// decimal, anything after that is optional.
+    fn header_size(header: &[u8]) -> Result<Option<usize>, Error> {
+        let mut fields = header.split(|b| *b == 0);
+
+        // An empty name means the sender has no file for us
+        if fields.next().is_none_or(|name| name.is_empty()) {
+            return Err(YmodemError::Cancelled.into());
+        }
+
+        let mut size = None;
+        for digit in fields
+            .next()
+            .unwrap_or_default()
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+        {
+            let next = size
+                .unwrap_or(0usize)
+                .checked_mul(10)
+                .and_then(|size| size.checked_add((digit - b'0') as usize))
+                .ok_or(YmodemError::BadHeader)?;
+            size = Some(next);
+        }
+
+        Ok(size)
+    }
+
+    /// Drops whatever is left of a damaged packet so the resend starts clean.
+    /// Gives up if the line doesn't go quiet.
+    fn purge() -> Result<(), Error> {
+        let deadline = Deadline::after_ms(PURGE_TIMEOUT_MS);
+
+        while !matches!(Serial::getc(Some(BYTE_TIMEOUT_MS)), Err(UARTError::Timeout)) {
+            Watchdog::kick();
+
+            if deadline.expired() {
+                return Err(YmodemError::Noise.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self, b: u8) {
+        let Ok(()) = self.serial.write(&[b]);
+    }
+}
+
+/// CRC-16/XMODEM, polynomial 0x1021 with a zero initial value.
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, b| {
+        let mut crc = crc ^ ((*b as u16) << 8);
+
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::uart;
+
+    fn receive(input: &[u8]) -> Result<usize, Error> {
+        uart::feed(input);
+
+        unsafe { Ymodem::new(Serial, DramSize::Dram32M).receive() }
+    }
+
+    fn packet(block: u8, data: &[u8]) -> Vec<u8> {
+        let mut payload = [0; SMALL_BLOCK];
+        payload[..data.len()].copy_from_slice(data);
+
+        let mut packet = vec![SOH, block, !block];
+        packet.extend_from_slice(&payload);
+        packet.extend_from_slice(&crc16(&payload).to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+    }
+
+    #[test]
+    fn needs_two_cans_to_cancel() {
+        uart::feed(&[CAN, CAN]);
+        assert!(matches!(
+            Ymodem::read_packet(&mut [0; LARGE_BLOCK], BYTE_TIMEOUT_MS),
+            Ok(Packet::Cancel)
+        ));
+
+        uart::feed(&[CAN, b'x']);
+        assert!(matches!(
+            Ymodem::read_packet(&mut [0; LARGE_BLOCK], BYTE_TIMEOUT_MS),
+            Err(Error::Ymodem(YmodemError::BadPacket))
+        ));
+    }
+
+    #[test]
+    fn ignores_eot_before_first_block() {
+        assert!(matches!(
+            receive(&[EOT, CAN, CAN]),
+            Err(Error::Ymodem(YmodemError::Cancelled))
+        ));
+    }
+
+    #[test]
+    fn rejects_eot_before_whole_file() {
+        let mut input = packet(0, b"u-boot.bin\x00300\x00");
+        input.push(EOT);
+
+        assert!(matches!(
+            receive(&input),
+            Err(Error::Ymodem(YmodemError::Truncated))
+        ));
+    }
+
+    #[test]
+    fn parses_header_size() {
+        assert!(matches!(
+            Ymodem::header_size(b"u-boot.bin\x0012345 14404416215 100644\x00\x00"),
+            Ok(Some(12345))
+        ));
+        assert!(matches!(
+            Ymodem::header_size(b"u-boot.bin\x00\x00"),
+            Ok(None)
+        ));
+        assert!(matches!(
+            Ymodem::header_size(b"\x00\x00"),
+            Err(Error::Ymodem(YmodemError::Cancelled))
+        ));
+        assert!(matches!(
+            Ymodem::header_size(b"u-boot.bin\x00123456789012345678901234567890\x00"),
+            Err(Error::Ymodem(YmodemError::BadHeader))
+        ));
+    }
+}
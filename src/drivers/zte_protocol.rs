@@ -1,21 +1,30 @@
 use core::slice;
 use derive_ctor::ctor;
-use ufmt::uwriteln;
 
 use crate::{
-    drivers::{uart::Serial, usb::Usb, writel},
+    drivers::{
+        uart::Serial,
+        usb::Usb,
+        watchdog::{self, Watchdog},
+        writel,
+    },
     err::Error,
+    log::{trace, warn},
+    timing::{BootTiming, Phase},
 };
 use simpleport::{SimpleRead, SimpleWrite};
 
 const SYNC_FLAG: u8 = 0x5a;
 const DOWNLOAD_FLAG: u8 = 0x7a;
 const RUN_FLAG: u8 = 0x8a;
+/// Our own, the ROM doesn't know it
+const REBOOT_FLAG: u8 = 0x9a;
 
 const SYNC_ACK: u8 = 0xa5;
 const DOWNLOAD_HEADER_ACK: u8 = 0xa1;
 const DOWNLOAD_COMPLETE_ACK: u8 = 0xa7;
 const RUN_ACK: u8 = 0xa8;
+const REBOOT_ACK: u8 = 0xa9;
 
 const IRAM1_BASE: usize = 0x100000;
 const A53_SUBSYS_CFG: usize = 0x013b138;
@@ -29,38 +38,88 @@ pub struct ZteProtocol {
 impl ZteProtocol {
     pub unsafe fn dispatch(&mut self) -> Result<(), Error> {
         loop {
-            let cmd = self.usb.read_u8()?;
+            Watchdog::kick();
 
-            match cmd {
-                SYNC_FLAG => self.usb.write_u8(SYNC_ACK)?,
-                DOWNLOAD_FLAG => unsafe {
-                    let addr = self.usb.read_u32_be()?;
-                    let size = self.usb.read_u32_be()?;
+            match unsafe { self.handle_command() } {
+                Ok(true) => break Ok(()),
+                Ok(false) => {}
+                Err(e) if e.is_recoverable() => {
+                    warn!("Protocol error: {}, resyncing", e);
+                    self.usb.flush_rx();
+                }
+                Err(e) => break Err(e),
+            }
+        }
+    }
+
+    /// Returns `true` once the AP has been started.
+    unsafe fn handle_command(&mut self) -> Result<bool, Error> {
+        let cmd = self.usb.read_u8()?;
+        trace!("ZTE: command {:#x}", cmd);
 
-                    self.usb.write_u8(DOWNLOAD_HEADER_ACK)?;
+        // Waiting for the host isn't counted, only the commands that move
+        // the boot along, the table would fill up with syncs otherwise
+        match cmd {
+            SYNC_FLAG => self.usb.write_u8(SYNC_ACK)?,
+            DOWNLOAD_FLAG => {
+                let phase = Phase::begin("download");
+                let result = unsafe { self.download() };
+                phase.end();
 
-                    self.usb
-                        .read(slice::from_raw_parts_mut(addr as *mut u8, size as usize))?;
+                result?;
+            }
+            RUN_FLAG => unsafe {
+                let phase = Phase::begin("run");
+                let addr = self.usb.read_u32_be();
+                phase.end();
 
-                    self.usb.write_u8(DOWNLOAD_COMPLETE_ACK)?;
-                },
-                RUN_FLAG => unsafe {
-                    let addr = self.usb.read_u32_be()?;
-                    Self::boot_ap(addr as usize);
+                Self::boot_ap(addr? as usize);
 
-                    self.usb.write_u8(RUN_ACK)?;
+                // The AP runs either way and owns the UART now, so a lost
+                // ack is neither reported nor an error
+                let _ = self.usb.write_u8(RUN_ACK);
 
-                    break Ok(());
-                },
-                _ => {
-                    uwriteln!(&mut Serial, "Unknown command: {:#x}", cmd);
-                }
+                return Ok(true);
+            },
+            // Left to the unknown command case without a watchdog
+            REBOOT_FLAG if watchdog::ENABLED => unsafe {
+                self.usb.write_u8(REBOOT_ACK)?;
+                Watchdog::reset();
+            },
+            _ => {
+                warn!("Unknown command: {:#x}", cmd);
             }
         }
+
+        Ok(false)
     }
 
-    unsafe fn boot_ap(uboot_entry: usize) {
+    unsafe fn download(&mut self) -> Result<(), Error> {
         unsafe {
+            let addr = self.usb.read_u32_be()?;
+            let size = self.usb.read_u32_be()?;
+
+            self.usb.write_u8(DOWNLOAD_HEADER_ACK)?;
+
+            self.usb
+                .read(slice::from_raw_parts_mut(addr as *mut u8, size as usize))?;
+
+            self.usb.write_u8(DOWNLOAD_COMPLETE_ACK)?;
+        }
+
+        Ok(())
+    }
+
+    pub unsafe fn boot_ap(uboot_entry: usize) {
+        unsafe { BootTiming::report() };
+
+        // The AP's first words would otherwise get mixed into our last ones
+        let _ = Serial::flush();
+
+        unsafe {
+            // Nothing kicks it once the AP runs
+            Watchdog::disable();
+
             writel(IRAM1_BASE, 0xe59ff000);
             writel(IRAM1_BASE + 8, uboot_entry);
             writel(A53_SUBSYS_CFG, A53_SW_RSTEN);
@@ -2,7 +2,11 @@ use ufmt::{uDisplay, uwrite};
 
 pub enum Error {
     DRAM,
+    PLL,
+    Efuse,
     USB(USBError),
+    UART(UARTError),
+    Ymodem(YmodemError),
 }
 
 impl From<USBError> for Error {
@@ -11,6 +15,29 @@ impl From<USBError> for Error {
     }
 }
 
+impl From<UARTError> for Error {
+    fn from(value: UARTError) -> Self {
+        Self::UART(value)
+    }
+}
+
+impl From<YmodemError> for Error {
+    fn from(value: YmodemError) -> Self {
+        Self::Ymodem(value)
+    }
+}
+
+impl Error {
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            Self::USB(usb) => usb.is_recoverable(),
+            Self::UART(uart) => uart.is_recoverable(),
+            Self::Ymodem(ymodem) => ymodem.is_recoverable(),
+            Self::DRAM | Self::PLL | Self::Efuse => false,
+        }
+    }
+}
+
 impl uDisplay for Error {
     fn fmt<W>(&self, f: &mut ufmt::Formatter<'_, W>) -> Result<(), W::Error>
     where
@@ -18,13 +45,31 @@ impl uDisplay for Error {
     {
         match self {
             Self::USB(usb) => uwrite!(f, "USB: {}", usb),
+            Self::UART(uart) => uwrite!(f, "UART: {}", uart),
+            Self::Ymodem(ymodem) => uwrite!(f, "YMODEM: {}", ymodem),
             Self::DRAM => uwrite!(f, "DRAM R/W test failed"),
+            Self::PLL => uwrite!(f, "PLL didn't lock"),
+            Self::Efuse => uwrite!(f, "Efuse read timed out"),
         }
     }
 }
 
 pub enum USBError {
     Timeout,
+    Babble,
+    FifoOverflow,
+    Disconnected,
+    Stalled,
+    ShortPacket,
+    UnexpectedSetup,
+}
+
+impl USBError {
+    /// Whether the protocol can drop what it was doing and wait for the next
+    /// command. A timeout means the host gave up on us.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self, Self::Timeout)
+    }
 }
 
 impl uDisplay for USBError {
@@ -34,6 +79,80 @@ impl uDisplay for USBError {
     {
         match self {
             Self::Timeout => uwrite!(f, "Timed out"),
+            Self::Babble => uwrite!(f, "Babble detected"),
+            Self::FifoOverflow => uwrite!(f, "FIFO overflow"),
+            Self::Disconnected => uwrite!(f, "Host disconnected"),
+            Self::Stalled => uwrite!(f, "Endpoint stalled"),
+            Self::ShortPacket => uwrite!(f, "Short packet"),
+            Self::UnexpectedSetup => uwrite!(f, "Unexpected SETUP packet"),
+        }
+    }
+}
+
+pub enum UARTError {
+    Framing,
+    Parity,
+    Break,
+    Overrun,
+    Timeout,
+    UnreachableBaud,
+}
+
+impl UARTError {
+    /// Line errors cost a byte, a bad baud rate needs a config change.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self, Self::UnreachableBaud)
+    }
+}
+
+impl uDisplay for UARTError {
+    fn fmt<W>(&self, f: &mut ufmt::Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: ufmt::uWrite + ?Sized,
+    {
+        match self {
+            Self::Framing => uwrite!(f, "Framing error"),
+            Self::Parity => uwrite!(f, "Parity error"),
+            Self::Break => uwrite!(f, "Break received"),
+            Self::Overrun => uwrite!(f, "RX FIFO overrun"),
+            Self::Timeout => uwrite!(f, "Timed out"),
+            Self::UnreachableBaud => uwrite!(f, "Baud rate unreachable"),
+        }
+    }
+}
+
+pub enum YmodemError {
+    BadPacket,
+    BadHeader,
+    Sequence,
+    TooLarge,
+    Truncated,
+    Noise,
+    TooManyErrors,
+    Cancelled,
+}
+
+impl YmodemError {
+    /// A damaged packet gets NAKed and resent, anything else ends the transfer.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, Self::BadPacket)
+    }
+}
+
+impl uDisplay for YmodemError {
+    fn fmt<W>(&self, f: &mut ufmt::Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: ufmt::uWrite + ?Sized,
+    {
+        match self {
+            Self::BadPacket => uwrite!(f, "Bad packet"),
+            Self::BadHeader => uwrite!(f, "Bad file size in header"),
+            Self::Sequence => uwrite!(f, "Block out of sequence"),
+            Self::TooLarge => uwrite!(f, "Image doesn't fit in DRAM"),
+            Self::Truncated => uwrite!(f, "Transfer ended before the whole image"),
+            Self::TooManyErrors => uwrite!(f, "Too many errors"),
+            Self::Noise => uwrite!(f, "Line never went quiet"),
+            Self::Cancelled => uwrite!(f, "Cancelled by sender"),
         }
     }
 }
@@ -0,0 +1,36 @@
+//! Small facts for the next stage, kept at the top of DRAM below the crash
+//! record. Written once DRAM is up.
+//!
+//! Layout, all words little endian:
+//!
+//! | Offset | Contents                                                |
+//! |--------|---------------------------------------------------------|
+//! | 0x00   | magic, `M0HO` in ASCII                                  |
+//! | 0x04   | reset reason, 1 power-on, 2 watchdog, 3 software, 4 A53 |
+
+use crate::drivers::dram::{DRAM_BASE, DramSize};
+use crate::drivers::reset::ResetReason;
+
+pub const HANDOFF_SIZE: usize = 8;
+
+const HANDOFF_MAGIC: u32 = u32::from_le_bytes(*b"M0HO");
+
+pub struct Handoff;
+
+impl Handoff {
+    /// Sits at the bottom of the reserved areas.
+    pub const fn base(size: DramSize) -> usize {
+        DRAM_BASE + size.usable_bytes()
+    }
+
+    pub unsafe fn store(size: DramSize, reset_reason: Option<ResetReason>) {
+        let record = Self::base(size) as *mut u32;
+
+        unsafe {
+            record
+                .add(1)
+                .write_volatile(reset_reason.map_or(0, |r| r as u32));
+            record.write_volatile(HANDOFF_MAGIC);
+        }
+    }
+}
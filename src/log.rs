@@ -0,0 +1,150 @@
+//! Leveled logging on top of `ufmt`.
+//!
+//! Messages above `MAX_LEVEL` compile to nothing, the level is picked with
+//! the `log-level-*` features and defaults to info. Output goes to every
+//! registered `Sink`, the console is always the first one.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use ufmt::uWrite;
+
+use crate::drivers::uart::Serial;
+
+const MAX_SINKS: usize = 4;
+
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const fn prefix(self) -> &'static str {
+        match self {
+            Self::Error => "error: ",
+            Self::Warn => "warning: ",
+            Self::Info => "",
+            Self::Debug => "debug: ",
+            Self::Trace => "trace: ",
+        }
+    }
+}
+
+/// The most verbose level compiled in, the most verbose feature wins.
+pub const MAX_LEVEL: Level = if cfg!(feature = "log-level-trace") {
+    Level::Trace
+} else if cfg!(feature = "log-level-debug") {
+    Level::Debug
+} else if cfg!(feature = "log-level-info") {
+    Level::Info
+} else if cfg!(feature = "log-level-warn") {
+    Level::Warn
+} else if cfg!(feature = "log-level-error") {
+    Level::Error
+} else {
+    Level::Info
+};
+
+/// Somewhere log output ends up. Gets the text in pieces, a message is only
+/// complete after its newline.
+pub trait Sink: Sync {
+    fn write(&self, bytes: &[u8]);
+}
+
+impl Sink for Serial {
+    fn write(&self, bytes: &[u8]) {
+        for b in bytes {
+            Serial::putc(*b);
+        }
+    }
+}
+
+struct Sinks {
+    list: UnsafeCell<[Option<&'static dyn Sink>; MAX_SINKS]>,
+    count: AtomicUsize,
+}
+
+unsafe impl Sync for Sinks {}
+
+static SINKS: Sinks = Sinks {
+    list: UnsafeCell::new([Some(&Serial), None, None, None]),
+    count: AtomicUsize::new(1),
+};
+
+/// Sends all further log output to `sink` as well, returns `false` if there
+/// is no room left. Only meant for init, before interrupt handlers log.
+pub unsafe fn add_sink(sink: &'static dyn Sink) -> bool {
+    let count = SINKS.count.load(Ordering::Relaxed);
+    if count == MAX_SINKS {
+        return false;
+    }
+
+    unsafe { (*SINKS.list.get())[count] = Some(sink) };
+    SINKS.count.store(count + 1, Ordering::Release);
+
+    true
+}
+
+/// Writer behind the macros, fans out to the registered sinks.
+pub struct Logger;
+
+impl Logger {
+    pub fn begin(level: Level) -> Self {
+        let mut logger = Self;
+        let Ok(()) = logger.write_str(level.prefix());
+
+        logger
+    }
+}
+
+impl uWrite for Logger {
+    type Error = core::convert::Infallible;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        let count = SINKS.count.load(Ordering::Acquire);
+        let sinks = unsafe { &(&*SINKS.list.get())[..count] };
+
+        for sink in sinks.iter().flatten() {
+            sink.write(s.as_bytes());
+        }
+
+        Ok(())
+    }
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        if ($level as u8) <= ($crate::log::MAX_LEVEL as u8) {
+            let mut logger = $crate::log::Logger::begin($level);
+            let _ = ufmt::uwriteln!(&mut logger, $($arg)+);
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+// Can't be defined as `warn`, the name clashes with the lint attribute
+macro_rules! warning {
+    ($($arg:tt)+) => { $crate::log::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log::log!($crate::log::Level::Trace, $($arg)+) };
+}
+
+pub(crate) use {debug, error, info, log, trace, warning as warn};
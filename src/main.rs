@@ -1,15 +1,34 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+// Host tests only exercise the drivers
+#![cfg_attr(test, allow(dead_code))]
 
+#[cfg(not(test))]
 use core::{arch::global_asm, panic::PanicInfo};
 
-use ufmt::uwriteln;
-
+/// Formatted messages would need `core::fmt`, which is kept out of the
+/// binary, the location is enough to find the spot. Only resets with the
+/// `watchdog` feature, otherwise the M0 halts here.
+#[cfg(not(test))]
 #[panic_handler]
-fn panic_handler(_: &PanicInfo) -> ! {
-    loop {}
+fn panic_handler(info: &PanicInfo) -> ! {
+    let message = info.message().as_str().unwrap_or("<formatted>");
+    let (file, line, column) = info
+        .location()
+        .map_or(("<unknown>", 0, 0), |l| (l.file(), l.line(), l.column()));
+
+    error!("Panic at {}:{}:{}: {}", file, line, column, message);
+
+    unsafe {
+        drivers::usb::report_panic();
+        Crash::record_panic(file, line, column, message);
+    }
+    let _ = Serial::flush();
+
+    unsafe { Watchdog::reset() }
 }
 
+#[cfg(not(test))]
 global_asm!(
     ".syntax unified
     .code 16
@@ -24,92 +43,221 @@ global_asm!(
         bx r0"
 );
 
+#[cfg(feature = "boot-log")]
+mod boot_log;
+mod crash;
 mod drivers;
 mod err;
+mod handoff;
+mod log;
+#[cfg(feature = "monitor")]
+mod monitor;
+mod ring;
+mod timing;
 use drivers::uart::Serial;
 
+#[cfg(feature = "boot-log")]
+use crate::boot_log::BootLog;
+use crate::crash::Crash;
 use crate::drivers::clk::pll::PLL;
 use crate::drivers::clk::soc::SoCClocks;
-use crate::drivers::dram::Dram;
+use crate::drivers::dram::{Dram, DramSize};
 use crate::drivers::efuse::Efuse;
 use crate::drivers::iram::IRAM;
+#[cfg(feature = "mass-storage")]
+use crate::drivers::mass_storage::MassStorage;
+#[cfg(feature = "interrupts")]
+use crate::drivers::nvic::Nvic;
+use crate::drivers::reset::ResetReason;
+use crate::drivers::timer::Timer;
 use crate::drivers::usb::Usb;
+use crate::drivers::vectors;
+use crate::drivers::watchdog::Watchdog;
+#[cfg(feature = "ymodem")]
+use crate::drivers::ymodem::Ymodem;
+#[cfg(not(feature = "mass-storage"))]
 use crate::drivers::zte_protocol::ZteProtocol;
 use crate::drivers::{Driver, DriverMut, StatelessDriver};
+use crate::err::Error;
+use crate::handoff::Handoff;
+use crate::log::{error, info};
+use crate::timing::{BootTiming, Phase};
 
 unsafe fn early_init() {
-    uwriteln!(&mut Serial, "Early init triggered");
+    info!("Early init triggered");
+
+    // Before the watchdog is touched
+    info!("Reset reason: {}", unsafe { ResetReason::detect() });
 
-    uwriteln!(&mut Serial, "PLL init");
-    unsafe { PLL::init() };
+    info!("Watchdog init");
+    unsafe { Watchdog::init() };
+
+    info!("PLL init");
+    let phase = Phase::begin("PLL");
+    if let Err(e) = unsafe { PLL::init() } {
+        error!("{}", e);
+    }
+    phase.end();
 
-    uwriteln!(&mut Serial, "Clock init");
-    unsafe { SoCClocks::init() };
+    info!("Clock init");
+    let phase = Phase::begin("clocks");
+    unsafe {
+        SoCClocks::init();
+        Timer::recalibrate();
+    }
+    phase.end();
 
-    uwriteln!(&mut Serial, "UART re-init");
+    // The divisors follow the clock setup above
+    info!("UART re-init");
     unsafe { Serial::init() };
 
-    uwriteln!(&mut Serial, "Early init finished");
+    #[cfg(feature = "interrupts")]
+    unsafe {
+        info!("Interrupt setup");
+        Nvic::init();
+        Serial::enable_interrupts();
+    }
+
+    info!("Early init finished");
 }
 
-unsafe fn init() {
-    uwriteln!(&mut Serial, "Init triggered");
+unsafe fn init() -> DramSize {
+    info!("Init triggered");
 
-    uwriteln!(&mut Serial, "IRAM setup");
+    info!("IRAM setup");
     unsafe { IRAM::init() };
 
-    uwriteln!(&mut Serial, "Efuse init");
-    let efuse = unsafe { Efuse::init() };
-    uwriteln!(&mut Serial, "Efuse provided info:");
-    uwriteln!(
-        &mut Serial,
+    info!("Efuse init");
+    let phase = Phase::begin("efuse");
+    let efuse = unsafe { Efuse::init() }.unwrap_or_else(|e| {
+        error!("{}, assuming defaults", e);
+        Efuse::default()
+    });
+    phase.end();
+    info!("Efuse provided info:");
+    info!(
         "\tFused device: {}",
         if efuse.secure { "yes" } else { "no" }
     );
-    uwriteln!(&mut Serial, "\tDRAM size: {}", efuse.dram_size);
+    info!("\tDRAM size: {}", efuse.dram_size);
 
-    uwriteln!(&mut Serial, "DRAM init");
+    info!("DRAM init");
     let dram = Dram::new(efuse.dram_size);
     unsafe {
         dram.init();
 
-        if let Err(e) = dram.verify() {
-            uwriteln!(&mut Serial, "Error on DRAM verification: {}", e);
+        let phase = Phase::begin("verify");
+        let result = dram.verify();
+        phase.end();
+
+        if let Err(e) = result {
+            error!("DRAM verification failed: {}", e);
         } else {
-            uwriteln!(&mut Serial, "DRAM R/W test pass");
+            info!("DRAM R/W test pass");
+            Handoff::store(efuse.dram_size, ResetReason::last());
+            BootTiming::attach(efuse.dram_size);
+            Crash::attach(efuse.dram_size);
+
+            #[cfg(feature = "boot-log")]
+            {
+                BootLog::attach(efuse.dram_size);
+                info!("Boot log at {:#x}", BootLog::base(efuse.dram_size));
+            }
         }
     }
 
-    uwriteln!(&mut Serial, "Init finished");
+    #[cfg(feature = "mmio-trace")]
+    drivers::mmio::trace::dump();
+
+    info!("Init finished");
+
+    efuse.dram_size
 }
 
-unsafe fn late_init() {
-    uwriteln!(&mut Serial, "Late init triggered");
+unsafe fn late_init(dram_size: DramSize) {
+    info!("Late init triggered");
 
     unsafe {
-        let mut usb = Usb::new();
-        usb.init();
+        #[cfg(feature = "ymodem")]
+        let result = Ymodem::new(Serial, dram_size).dispatch();
+        #[cfg(not(feature = "ymodem"))]
+        let result = usb_download(dram_size);
 
-        let mut protocol = ZteProtocol::new(usb);
-        if let Err(e) = protocol.dispatch() {
-            uwriteln!(&mut Serial, "Error on running protocol: {}", e);
+        if let Err(e) = result {
+            error!("Protocol failed: {}", e);
         }
     }
 
-    uwriteln!(&mut Serial, "Late init finished");
+    info!("Late init finished");
+}
+
+/// Only reachable from the monitor when the UART is the download path
+#[cfg_attr(feature = "ymodem", allow(dead_code))]
+#[cfg_attr(not(feature = "mass-storage"), allow(unused_variables))]
+unsafe fn usb_download(dram_size: DramSize) -> Result<(), Error> {
+    unsafe {
+        #[cfg(feature = "mass-storage")]
+        let mut usb = Usb::new(Some(&MassStorage::IDENTITY));
+        // Whatever IDs the ROM enumerated with are what the host tool
+        // looks for, so don't present any of our own
+        #[cfg(not(feature = "mass-storage"))]
+        let mut usb = Usb::new(None);
+        let phase = Phase::begin("USB init");
+        usb.init();
+        phase.end();
+        #[cfg(feature = "interrupts")]
+        usb.enable_interrupts();
+
+        #[cfg(feature = "mass-storage")]
+        let mut protocol = MassStorage::new(usb, dram_size);
+        #[cfg(not(feature = "mass-storage"))]
+        let mut protocol = ZteProtocol::new(usb);
+
+        protocol.dispatch()
+    }
 }
 
+#[cfg(not(test))]
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn main() -> ! {
-    uwriteln!(&mut Serial, "Hello from Rust :)");
+    unsafe {
+        vectors::install();
+        // Everything from here on is timed with it, output waiting for the
+        // UART included
+        Timer::init();
+    }
 
+    #[cfg(feature = "boot-log")]
     unsafe {
+        log::add_sink(&BootLog)
+    };
+
+    info!("Hello from Rust :)");
+
+    unsafe {
+        let phase = Phase::begin("early");
         early_init();
-        init();
-        late_init();
+        phase.end();
+
+        let dram_size = init();
+
+        #[cfg(feature = "monitor")]
+        if monitor::should_stop() {
+            monitor::run(dram_size);
+        }
+
+        late_init(dram_size);
     }
 
-    uwriteln!(&mut Serial, "All done, spinning forever");
+    info!("All done, spinning forever");
+    park()
+}
+
+/// Where the M0 ends up once the AP is started, from then on the UART is the
+/// AP's and nothing of ours may run anymore.
+pub fn park() -> ! {
+    // Nothing left to report a stuck UART to
+    let _ = Serial::flush();
 
     loop {}
 }
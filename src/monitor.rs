@@ -0,0 +1,259 @@
+use core::str;
+
+use ufmt::{uwrite, uwriteln};
+
+use crate::drivers::clk::pll::PLL;
+use crate::drivers::dram::{Dram, DramSize};
+use crate::drivers::efuse::{EFUSE_RAM_BASE, EFUSE_RAM_SIZE, Efuse};
+use crate::drivers::reset::ResetReason;
+use crate::drivers::uart::{Serial, UartConfig};
+use crate::drivers::watchdog::Watchdog;
+#[cfg(feature = "ymodem")]
+use crate::drivers::ymodem::Ymodem;
+use crate::drivers::zte_protocol::ZteProtocol;
+use crate::drivers::{readl, writel};
+
+/// How long `should_stop` listens for a key
+const AUTOBOOT_TIMEOUT_MS: u64 = 3_000;
+const MAX_LINE: usize = 64;
+const MAX_ARGS: usize = 4;
+const MD_DEFAULT_WORDS: usize = 16;
+
+const PROMPT: &str = "openloader> ";
+const HELP: &str = "Commands:
+\tmd <addr> [words]\tdisplay memory
+\tmw <addr> <value>\twrite a word
+\tinfo\t\t\tshow reset reason and DRAM size
+\tefuse\t\t\tdump the fuses
+\tdram test\t\trun the DRAM R/W test
+\tclk\t\t\tshow PLL status
+\tbaud <rate>\t\tchange the console baud rate (decimal)
+\tusb\t\t\tenter USB download mode
+\tgo <addr>\t\tstart the AP at addr";
+#[cfg(feature = "ymodem")]
+const HELP_YMODEM: &str = "\tloady\t\t\tXMODEM/YMODEM download and boot";
+#[cfg(feature = "watchdog")]
+const HELP_RESET: &str = "\treset\t\t\treset the SoC";
+
+/// Gives the user a chance to break into the monitor before we go on with
+/// the normal boot.
+pub fn should_stop() -> bool {
+    uwriteln!(&mut Serial, "Press any key to enter the monitor");
+
+    Serial::getc(Some(AUTOBOOT_TIMEOUT_MS)).is_ok()
+}
+
+pub unsafe fn run(dram_size: DramSize) -> ! {
+    let mut line = [0; MAX_LINE];
+
+    // Someone is at the prompt, they can take as long as they like
+    unsafe { Watchdog::disable() };
+
+    help();
+
+    loop {
+        uwrite!(&mut Serial, "{}", PROMPT);
+
+        let len = read_line(&mut line);
+        let Ok(line) = str::from_utf8(&line[..len]) else {
+            uwriteln!(&mut Serial, "Invalid input");
+            continue;
+        };
+
+        let mut args = [""; MAX_ARGS];
+        let mut argc = 0;
+        for arg in line.split_ascii_whitespace().take(MAX_ARGS) {
+            args[argc] = arg;
+            argc += 1;
+        }
+
+        if argc != 0 && unsafe { !dispatch(&args[..argc], dram_size) } {
+            help();
+        }
+    }
+}
+
+fn help() {
+    uwriteln!(&mut Serial, "{}", HELP);
+    #[cfg(feature = "ymodem")]
+    uwriteln!(&mut Serial, "{}", HELP_YMODEM);
+    #[cfg(feature = "watchdog")]
+    uwriteln!(&mut Serial, "{}", HELP_RESET);
+}
+
+/// Returns `false` if the command or its arguments didn't make sense.
+unsafe fn dispatch(args: &[&str], dram_size: DramSize) -> bool {
+    match args {
+        ["md", addr] => unsafe { md(parse(addr), Some(MD_DEFAULT_WORDS)) },
+        ["md", addr, words] => unsafe { md(parse(addr), parse(words)) },
+        ["mw", addr, value] => match (parse(addr), parse(value)) {
+            (Some(addr), Some(value)) => {
+                if is_aligned(addr) {
+                    unsafe { writel(addr, value) };
+                }
+                true
+            }
+            _ => false,
+        },
+        ["info"] => {
+            match ResetReason::last() {
+                Some(reason) => uwriteln!(&mut Serial, "Reset reason: {}", reason),
+                None => uwriteln!(&mut Serial, "Reset reason: unknown"),
+            };
+            uwriteln!(&mut Serial, "DRAM size: {}", dram_size);
+            true
+        }
+        ["efuse"] => unsafe {
+            let efuse = match Efuse::init() {
+                Ok(efuse) => efuse,
+                Err(e) => {
+                    uwriteln!(&mut Serial, "Error: {}", e);
+                    return true;
+                }
+            };
+            uwriteln!(
+                &mut Serial,
+                "Fused device: {}",
+                if efuse.secure { "yes" } else { "no" }
+            );
+            uwriteln!(&mut Serial, "DRAM size: {}", efuse.dram_size);
+
+            md(Some(EFUSE_RAM_BASE), Some(EFUSE_RAM_SIZE / 4))
+        },
+        ["dram", "test"] => {
+            match unsafe { Dram::new(dram_size).verify() } {
+                Ok(()) => uwriteln!(&mut Serial, "DRAM R/W test pass"),
+                Err(e) => uwriteln!(&mut Serial, "Error on DRAM verification: {}", e),
+            };
+            true
+        }
+        ["clk"] => {
+            for pll in unsafe { PLL::status() } {
+                uwriteln!(
+                    &mut Serial,
+                    "{}: {:#x} ({})",
+                    pll.name,
+                    pll.cfg,
+                    if pll.locked { "locked" } else { "unlocked" }
+                );
+            }
+            true
+        }
+        ["baud", rate] => match rate.parse() {
+            Ok(baud) => {
+                let config = UartConfig {
+                    baud,
+                    ..UartConfig::DEFAULT
+                };
+                uwriteln!(&mut Serial, "Switching to {} baud", baud);
+                if let Err(e) = unsafe { Serial::configure(&config) } {
+                    uwriteln!(&mut Serial, "Error: {}", e);
+                }
+                true
+            }
+            Err(_) => false,
+        },
+        // Both only return successfully once the AP runs
+        ["usb"] => match unsafe { crate::usb_download(dram_size) } {
+            Ok(()) => crate::park(),
+            Err(e) => {
+                uwriteln!(&mut Serial, "Error on running protocol: {}", e);
+                true
+            }
+        },
+        #[cfg(feature = "ymodem")]
+        ["loady"] => match unsafe { Ymodem::new(Serial, dram_size).dispatch() } {
+            Ok(()) => crate::park(),
+            Err(e) => {
+                uwriteln!(&mut Serial, "Error on running protocol: {}", e);
+                true
+            }
+        },
+        #[cfg(feature = "watchdog")]
+        ["reset"] => {
+            let _ = Serial::flush();
+            unsafe { Watchdog::reset() }
+        }
+        ["go", addr] => match parse(addr) {
+            Some(addr) => {
+                uwriteln!(&mut Serial, "Starting AP at {:#x}", addr);
+                unsafe { ZteProtocol::boot_ap(addr) };
+                crate::park()
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+unsafe fn md(addr: Option<usize>, words: Option<usize>) -> bool {
+    let (Some(addr), Some(words)) = (addr, words) else {
+        return false;
+    };
+    if !is_aligned(addr) {
+        return true;
+    }
+
+    for i in 0..words {
+        let word = addr + i * 4;
+
+        if i % 4 == 0 {
+            if i != 0 {
+                uwriteln!(&mut Serial, "");
+            }
+            uwrite!(&mut Serial, "{:#x}:", word);
+        }
+
+        uwrite!(&mut Serial, " {:#x}", unsafe { readl(word) });
+    }
+    uwriteln!(&mut Serial, "");
+
+    true
+}
+
+/// The M0 faults on unaligned word accesses, so they are refused with an
+/// error instead.
+fn is_aligned(addr: usize) -> bool {
+    if !addr.is_multiple_of(4) {
+        uwriteln!(&mut Serial, "Error: {:#x} isn't word aligned", addr);
+        return false;
+    }
+
+    true
+}
+
+/// Numbers are hex, with or without the `0x`, like in U-Boot.
+fn parse(s: &str) -> Option<usize> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+
+    usize::from_str_radix(s, 16).ok()
+}
+
+/// Reads up to the next carriage return with echo and backspace handling,
+/// returns the line length.
+fn read_line(buf: &mut [u8]) -> usize {
+    let mut len = 0;
+
+    loop {
+        let Ok(c) = Serial::getc(None) else {
+            continue;
+        };
+
+        match c {
+            b'\r' | b'\n' => {
+                Serial::putc(b'\n');
+                break len;
+            }
+            0x08 | 0x7f if len != 0 => {
+                len -= 1;
+                uwrite!(&mut Serial, "\x08 \x08");
+            }
+            c if len < buf.len() && (c.is_ascii_graphic() || c == b' ') => {
+                buf[len] = c;
+                len += 1;
+                Serial::putc(c);
+            }
+            _ => {}
+        }
+    }
+}
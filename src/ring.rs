@@ -0,0 +1,54 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Single producer, single consumer byte queue shared between an interrupt
+/// handler and the main loop. One slot is kept free to tell full from empty.
+pub struct RingBuffer<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl<const N: usize> Sync for RingBuffer<N> {}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns `false` and drops the byte if the queue is full.
+    pub fn push(&self, b: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let next = (head + 1) % N;
+
+        if next == self.tail.load(Ordering::Acquire) {
+            return false;
+        }
+
+        unsafe { (*self.buf.get())[head] = b };
+        self.head.store(next, Ordering::Release);
+
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tail.load(Ordering::Relaxed) == self.head.load(Ordering::Acquire)
+    }
+
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let b = unsafe { (*self.buf.get())[tail] };
+        self.tail.store((tail + 1) % N, Ordering::Release);
+
+        Some(b)
+    }
+}
@@ -0,0 +1,170 @@
+//! Boot phase timing, to see where the time to U-Boot goes.
+//!
+//! Phases are timed with `Timer`, so nothing before `Timer::init` is covered.
+//! With the `boot-timing` feature the table is printed right before the AP is
+//! started and copied to the top of DRAM, right below the boot log area.
+//! Without it nothing is recorded.
+//!
+//! Layout, all words little endian:
+//!
+//! | Offset | Contents                         |
+//! |--------|----------------------------------|
+//! | 0x00   | magic, `M0TM` in ASCII           |
+//! | 0x04   | number of entries                |
+//! | 0x08   | entries, 16 bytes each           |
+//!
+//! An entry is the phase name NUL padded to 8 bytes, the start and the
+//! duration in microseconds. Phases still running have a duration of
+//! `0xffffffff`.
+
+use core::cell::UnsafeCell;
+
+use crate::crash::{CRASH_SIZE, Crash};
+use crate::drivers::dram::DramSize;
+use crate::drivers::timer::Timer;
+use crate::log::info;
+
+const ENABLED: bool = cfg!(feature = "boot-timing");
+/// Entries the DRAM copy has room for, reserved even without `boot-timing`
+const SLOTS: usize = 32;
+const CAPACITY: usize = if ENABLED { SLOTS } else { 0 };
+const NAME_LEN: usize = 8;
+const RUNNING: u32 = u32::MAX;
+
+const TIMING_MAGIC: u32 = u32::from_le_bytes(*b"M0TM");
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = NAME_LEN + 8;
+pub const TIMING_SIZE: usize = HEADER_SIZE + SLOTS * ENTRY_SIZE;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    name: &'static str,
+    start_us: u32,
+    duration_us: u32,
+}
+
+struct State {
+    entries: [Entry; CAPACITY],
+    len: usize,
+    /// Null until DRAM is usable
+    base: *mut u32,
+}
+
+struct StateCell(UnsafeCell<State>);
+
+unsafe impl Sync for StateCell {}
+
+static STATE: StateCell = StateCell(UnsafeCell::new(State {
+    entries: [Entry {
+        name: "",
+        start_us: 0,
+        duration_us: RUNNING,
+    }; CAPACITY],
+    len: 0,
+    base: core::ptr::null_mut(),
+}));
+
+/// A phase being timed, it only counts as finished once `end` is called.
+/// Phases past the capacity of the table are dropped.
+#[must_use]
+pub struct Phase(Option<usize>);
+
+impl Phase {
+    /// Names longer than 8 bytes are cut short in the stored table.
+    pub fn begin(name: &'static str) -> Self {
+        if !ENABLED {
+            return Self(None);
+        }
+
+        let state = unsafe { &mut *STATE.0.get() };
+        if state.len == CAPACITY {
+            return Self(None);
+        }
+
+        let index = state.len;
+        state.entries[index] = Entry {
+            name,
+            start_us: Timer::micros() as u32,
+            duration_us: RUNNING,
+        };
+        state.len += 1;
+
+        Self(Some(index))
+    }
+
+    pub fn end(self) {
+        let Some(index) = self.0 else {
+            return;
+        };
+
+        let entry = unsafe { &mut (*STATE.0.get()).entries[index] };
+        entry.duration_us = (Timer::micros() as u32).wrapping_sub(entry.start_us);
+    }
+}
+
+pub struct BootTiming;
+
+impl BootTiming {
+    /// Sits between the crash record and the boot log.
+    pub const fn base(size: DramSize) -> usize {
+        Crash::base(size) + CRASH_SIZE
+    }
+
+    /// Lets `report` store the table in DRAM.
+    pub unsafe fn attach(size: DramSize) {
+        if ENABLED {
+            unsafe { (*STATE.0.get()).base = Self::base(size) as *mut u32 };
+        }
+    }
+
+    /// Prints the table, and stores it if DRAM is attached. Meant to be
+    /// called just before the AP takes over.
+    pub unsafe fn report() {
+        if !ENABLED {
+            return;
+        }
+
+        let state = unsafe { &*STATE.0.get() };
+        let entries = &state.entries[..state.len];
+
+        info!("Boot timing, start and duration in us:");
+        for entry in entries {
+            if entry.duration_us == RUNNING {
+                info!("\t{}\t{}\trunning", entry.name, entry.start_us);
+            } else {
+                info!(
+                    "\t{}\t{}\t{}",
+                    entry.name, entry.start_us, entry.duration_us
+                );
+            }
+        }
+        info!("\ttotal\t{}", Timer::micros() as u32);
+
+        if state.base.is_null() {
+            return;
+        }
+
+        unsafe {
+            let mut word = state.base;
+            let mut push = |value: u32| {
+                word.write_volatile(value);
+                word = word.add(1);
+            };
+
+            push(TIMING_MAGIC);
+            push(entries.len() as u32);
+
+            for entry in entries {
+                let mut name = [0; NAME_LEN];
+                let len = entry.name.len().min(NAME_LEN);
+                name[..len].copy_from_slice(&entry.name.as_bytes()[..len]);
+
+                for chunk in name.chunks(4) {
+                    push(u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+                }
+                push(entry.start_us);
+                push(entry.duration_us);
+            }
+        }
+    }
+}
@@ -0,0 +1,16 @@
+//! Stand-in crate root for `src/drivers/regs.rs`, cargo doesn't run doctests
+//! of a binary. Check the access mode examples with
+//! `rustdoc --test --edition 2024 tools/regs_doctest.rs`.
+
+#![allow(dead_code, unused_imports, unused_macros)]
+
+#[path = "../src/drivers"]
+mod drivers {
+    pub unsafe fn readl(_: usize) -> usize {
+        0
+    }
+
+    pub unsafe fn writel(_: usize, _: usize) {}
+
+    pub mod regs;
+}
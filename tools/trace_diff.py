@@ -0,0 +1,78 @@
+#!/usr/bin/env python3
+"""Diffs two MMIO traces as printed by the `mmio-trace` feature.
+
+Each trace is a UART log, lines that aren't trace entries are skipped:
+
+    MMIO <seq> <R|W> <addr> <value>
+
+The sequence number is optional, as in the golden traces under testdata/.
+
+Runs of identical reads are folded into one since the number of polls
+before a status bit flips differs between boots. Exits with 1 if the traces
+differ.
+"""
+
+import argparse
+import difflib
+import re
+import sys
+
+ENTRY = re.compile(r"MMIO (?:\d+ )?([RW]) (0x[0-9a-fA-F]+) (0x[0-9a-fA-F]+)")
+
+
+def parse(path, writes_only):
+    entries = []
+
+    with open(path, errors="replace") as f:
+        for line in f:
+            match = ENTRY.search(line)
+            if not match:
+                continue
+
+            direction, addr, value = match.groups()
+            if writes_only and direction == "R":
+                continue
+
+            entry = f"{direction} {int(addr, 16):#010x} {int(value, 16):#010x}"
+            if direction == "R" and entries and entries[-1] == entry:
+                continue
+
+            entries.append(entry)
+
+    return entries
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("expected", help="reference trace, e.g. from the vendor blob")
+    parser.add_argument("actual", help="trace to check")
+    parser.add_argument("-w", "--writes-only", action="store_true", help="ignore reads")
+    parser.add_argument("-c", "--context", type=int, default=3, help="lines of context")
+    args = parser.parse_args()
+
+    expected = parse(args.expected, args.writes_only)
+    actual = parse(args.actual, args.writes_only)
+
+    diff = list(
+        difflib.unified_diff(
+            expected,
+            actual,
+            args.expected,
+            args.actual,
+            n=args.context,
+            lineterm="",
+        )
+    )
+
+    for line in diff:
+        print(line)
+
+    if diff:
+        return 1
+
+    print(f"Traces match ({len(expected)} accesses)")
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())